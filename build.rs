@@ -1,8 +1,19 @@
 extern crate gcc;
 
+use std::env;
+
 fn main() {
+    let version = env::var("CARGO_PKG_VERSION").unwrap();
+    let major = env::var("CARGO_PKG_VERSION_MAJOR").unwrap();
+    let minor = env::var("CARGO_PKG_VERSION_MINOR").unwrap();
+    let patch = env::var("CARGO_PKG_VERSION_PATCH").unwrap();
+
     gcc::Config::new()
         .include("src")
+        .define("VERSION", Some(&format!("\"{}\"", version)))
+        .define("VERSION_MAJOR", Some(&major))
+        .define("VERSION_MINOR", Some(&minor))
+        .define("VERSION_PATCH", Some(&patch))
         .flag("-g")
         .flag("-Os")
         .flag("-I/usr/include")
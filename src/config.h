@@ -23,7 +23,7 @@ static char *utmp = NULL;
 static char stty_args[] = "stty raw pass8 nl -echo -iexten -cstopb 38400";
 
 /* identification sequence returned in DA and DECID */
-static char vtiden[] = "\033[?6c";
+static char vtiden[] = "\033[?62;4c";
 
 /*
  * word delimiter string
@@ -34,6 +34,14 @@ static char worddelimiters[] = " ";
 
 /* alt screens */
 extern int allowaltscreen;
+extern int allowlegacymouse;
+
+/* XTWINOPS operations, see config.rs */
+extern int allowwindowops[];
+
+/* OSC 52 selection access, see config.rs */
+extern int allowclipread;
+extern int allowclipwrite;
 
 /* frames per second st should at maximum draw to the screen */
 static unsigned int xfps = 120;
@@ -51,7 +59,10 @@ static unsigned int blinktimeout = 800;
  */
 static int bellvolume = 0;
 
-/* default TERM value */
+/*
+ * default TERM value. REP is supported, so its terminfo entry may
+ * advertise rep=%p1%c\E[%p2%{1}%-%db,
+ */
 static char termname[] = "st-256color";
 
 /*
@@ -43,6 +43,24 @@ pub const cursorshape: c_int = 2;
  */
 pub const cursorthickness: c_uint = 2;
 
+/*
+ * blinking timeout (in milliseconds) for the blinking cursor styles, set to 0
+ * to draw them steadily.
+ */
+pub const cursorblinktimeout: c_long = 600;
+
+/*
+ * longest time (in milliseconds) a synchronized update (mode 2026) may hold
+ * back drawing, so an application that dies mid-update can't freeze the screen
+ */
+pub const synctimeout: c_long = 150;
+
+/*
+ * how keys are sent under xterm's modifyOtherKeys: 0 for CSI 27 ; mods ;
+ * key ~, 1 for CSI key ; mods u
+ */
+pub const formatotherkeys: c_int = 0;
+
 pub const defaultfont: &'static str = "Liberation Mono:pixelsize=16:antialias=true:autohint=true";
 pub const borderpx: c_int = 2;
 
@@ -117,6 +135,39 @@ pub static ignoremod: c_uint = Mod2Mask|XK_SWITCH_MOD;
  */
 pub const forceselmod: c_uint = ShiftMask;
 
+/*
+ * Whether programs may set (write) or query (read) the clipboard and primary
+ * selection with OSC 52. Reading is off by default, since anything printed to
+ * the terminal, such as `cat`ing a file, could then see what you last copied.
+ */
+#[no_mangle]
+pub static allowclipwrite: c_int = 1;
+#[no_mangle]
+pub static allowclipread: c_int = 0;
+
+/*
+ * Whether programs may use the urxvt (1015) and UTF-8 (1005) mouse encodings.
+ * Off by default, as applications that don't expect them can mistake the
+ * reports for other input. SGR (1006) and SGR-Pixels (1016) are always on.
+ */
+#[no_mangle]
+pub static allowlegacymouse: c_int = 0;
+
+/*
+ * XTWINOPS (CSI Ps t) operations programs may use, by their Ps, ended by 0.
+ * Refreshing, the size and state reports and the title stack are allowed.
+ * Moving, resizing, iconifying, raising or lowering the window (1-6, 8) and
+ * reading the title back (20, 21) are not, so that untrusted output can't.
+ */
+#[no_mangle]
+pub static allowwindowops: [c_int; 11] = [7, 11, 13, 14, 15, 16, 18, 19, 22, 23, 0];
+
+/*
+ * Command used to open an OSC 8 hyperlink when it is ctrl-clicked. The URI is
+ * passed as its only argument.
+ */
+pub const linkopener: &'static str = "xdg-open";
+
 /* selection timeouts (in milliseconds) */
 pub const doubleclicktimeout: c_long = 300;
 pub const tripleclicktimeout: c_long = 600;
@@ -21,6 +21,10 @@ extern crate errno;
 use errno::errno;
 
 use std::ffi::CString;
+use std::ffi::CStr;
+use std::process::Command;
+use std::path::PathBuf;
+use std::thread;
 use std::mem;
 use std::ptr;
 use std::cmp::max;
@@ -106,7 +110,7 @@ const MODKEY: c_uint = xlib::Mod1Mask;
 const XK_ANY_MOD: c_uint = 0xFFFFFFFF;
 const XK_NO_MOD: c_uint = 0;
 
-static shortcuts: [Shortcut; 14] = [Shortcut {
+static shortcuts: [Shortcut; 17] = [Shortcut {
                                         key_mod: XK_ANY_MOD,
                                         key_sym: XK_Break,
                                         call: NoArg(sendbreak),
@@ -175,6 +179,21 @@ static shortcuts: [Shortcut; 14] = [Shortcut {
                                         key_mod: ShiftMask,
                                         key_sym: XK_Page_Down,
                                         call: Int(kscrolldown, -1),
+                                    },
+                                    Shortcut {
+                                        key_mod: ControlMask | ShiftMask,
+                                        key_sym: XK_Page_Up,
+                                        call: Int(kpromptup, 1),
+                                    },
+                                    Shortcut {
+                                        key_mod: ControlMask | ShiftMask,
+                                        key_sym: XK_Page_Down,
+                                        call: Int(kpromptdown, 1),
+                                    },
+                                    Shortcut {
+                                        key_mod: ControlMask | ShiftMask,
+                                        key_sym: XK_Return,
+                                        call: NoArg(newterm),
                                     }];
 
 /*
@@ -294,6 +313,48 @@ fn kscrolldown(n: c_int) {
         c_kscrolldown(n);
     }
 }
+fn kpromptup(n: c_int) {
+    unsafe {
+        c_kpromptup(n);
+    }
+}
+fn kpromptdown(n: c_int) {
+    unsafe {
+        c_kpromptdown(n);
+    }
+}
+
+// opens another stru in the shell's working directory
+fn newterm() {
+    let cwd = unsafe {
+        if term.cwd.is_null() {
+            std::fs::read_link(format!("/proc/{}/cwd", shellpid())).ok()
+        } else {
+            Some(PathBuf::from(CStr::from_ptr(term.cwd).to_string_lossy().into_owned()))
+        }
+    };
+
+    let exe = std::env::current_exe().unwrap_or(PathBuf::from("stru"));
+    let mut cmd = Command::new(exe);
+    // a directory that's gone would fail the spawn, so stay where we are
+    if let Some(dir) = cwd.filter(|dir| dir.is_dir()) {
+        cmd.current_dir(dir);
+    }
+    spawn(&mut cmd);
+}
+
+fn spawn(cmd: &mut Command) {
+    match cmd.spawn() {
+        Ok(mut child) => {
+            // reap it here since the SIGCHLD handler only waits on the shell
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => {
+            use std::io::Write;
+            let _ = writeln!(&mut std::io::stderr(), "stru: couldn't run {:?}: {}", cmd, e);
+        }
+    }
+}
 
 fn x_mod_match(mask: c_uint, state: c_uint) -> bool {
     mask == XK_ANY_MOD || mask == (state & !config::ignoremod)
@@ -302,7 +363,7 @@ fn x_mod_match(mask: c_uint, state: c_uint) -> bool {
 // "the `link_args` attribute is not portable across platforms" but that's fine,
 // I just need it for the purposes of the port and only until I can move everything
 // over to the rust X11 bindings
-#[link_args = "-L/usr/lib -lc -L/usr/X11R6/lib -lm -lrt -lX11 -lutil -lXft -lfontconfig -lfreetype"]
+#[link_args = "-L/usr/lib -lc -L/usr/X11R6/lib -lm -lrt -lX11 -lutil -lXft -lXrender -lfontconfig -lfreetype -lpng -lz"]
 extern "C" {
     fn st_main(argc: c_int,
                argv: *const *const c_char,
@@ -325,6 +386,8 @@ extern "C" {
                            x: c_int,
                            y: c_int);
 
+    fn xdrawimage(g: Glyph, x: c_int, y: c_int);
+
     fn xloadfont(font: *mut Font, pattern: *mut FcPattern) -> c_int;
 
     fn selected(x: c_int, y: c_int) -> c_int;
@@ -332,6 +395,7 @@ extern "C" {
     fn selnormalize();
 
     fn ttynew();
+    fn shellpid() -> pid_t;
     fn ttyresize();
     fn ttyread() -> size_t;
     fn ttysend(s: *const c_char, n: size_t);
@@ -372,8 +436,12 @@ extern "C" {
     fn c_numlock(arg: *const c_void);
     fn c_kscrollup(n: c_int);
     fn c_kscrolldown(n: c_int);
+    fn c_kpromptup(n: c_int);
+    fn c_kpromptdown(n: c_int);
 
     fn kmap(k: xlib::KeySym, state: c_uint) -> *mut c_char;
+
+    fn linkuri(link: c_ushort) -> *const c_char;
 }
 
 //  a88888b.                              dP
@@ -399,6 +467,7 @@ const YNegative: c_int = 0x0020;
 /* Arbitrary sizes */
 const UTF_INVALID: c_int = 0xFFFD;
 const UTF_SIZ: usize = 4;
+const KBD_STACK_SIZ: usize = 8;
 
 // 8888ba.88ba
 // 88  `8b  `8b
@@ -508,7 +577,8 @@ macro_rules! is_between {
 
 macro_rules! attr_cmp {
     ( $a: expr, $b: expr) => {
-        $a.mode != $b.mode || $a.fg != $b.fg || $a.bg != $b.bg
+        $a.mode != $b.mode || $a.fg != $b.fg || $a.bg != $b.bg ||
+        $a.ul != $b.ul || $a.ulcolor != $b.ulcolor
     }
 }
 
@@ -518,8 +588,14 @@ macro_rules! new {
             attr: Glyph {
                 u: 0,
                 mode: ATTR_NULL as u16,
+                link: 0,
                 fg: config::defaultfg,
                 bg: config::defaultbg,
+                img: 0,
+                imgx: 0,
+                imgy: 0,
+                ul: 0,
+                ulcolor: 0,
             },
             x: 0,
             y: 0,
@@ -606,6 +682,7 @@ enum selection_snap {
     SNAP_NONE = 0,
     SNAP_WORD = 1,
     SNAP_LINE = 2,
+    SNAP_OUTPUT = 3,
 }
 use selection_snap::*;
 
@@ -634,6 +711,16 @@ enum term_mode {
     MODE_MOUSEMANY = 1 << 18,
     MODE_BRCKTPASTE = 1 << 19,
     MODE_PRINT = 1 << 20,
+    MODE_CBLINK = 1 << 21, /* cursor blink phase, hidden if set */
+    MODE_SYNC = 1 << 22,
+    MODE_MOUSEPIXELS = 1 << 23,
+    MODE_MOUSEURXVT = 1 << 24,
+    MODE_MOUSEUTF8 = 1 << 25,
+    MODE_LRMM = 1 << 26,
+    MODE_ALLOWCOLM = 1 << 27,
+    MODE_132COLS = 1 << 28,
+    MODE_RECTEXT = 1 << 29,
+    MODE_VT52 = 1 << 30,
     MODE_MOUSE = MODE_MOUSEBTN as isize
         |MODE_MOUSEMOTION as isize
         |MODE_MOUSEX10 as isize
@@ -656,7 +743,14 @@ enum glyph_attribute {
     ATTR_WRAP = 1 << 8,
     ATTR_WIDE = 1 << 9,
     ATTR_WDUMMY = 1 << 10,
+    ATTR_PROMPT = 1 << 11, /* OSC 133 marks, kept by tsetchar */
+    ATTR_INPUT = 1 << 12,
+    ATTR_OUTPUT = 1 << 13,
+    ATTR_DONE = 1 << 14,
+    ATTR_PROTECTED = 1 << 15, /* DECSCA, skipped by DECSED and DECSEL */
     ATTR_BOLD_FAINT = ATTR_BOLD as isize | ATTR_FAINT as isize,
+    ATTR_MARKS = ATTR_PROMPT as isize | ATTR_INPUT as isize | ATTR_OUTPUT as isize |
+                 ATTR_DONE as isize,
 }
 use glyph_attribute::*;
 
@@ -670,6 +764,17 @@ enum charset {
     CS_MULTI = 4,
     CS_GER = 5,
     CS_FIN = 6,
+    CS_TECH = 7,
+    CS_LATIN1 = 8,
+    CS_DUT = 9,
+    CS_FRE = 10,
+    CS_FRECAN = 11,
+    CS_ITA = 12,
+    CS_NOR = 13,
+    CS_POR = 14,
+    CS_SPA = 15,
+    CS_SWE = 16,
+    CS_SWI = 17,
 }
 use charset::*;
 
@@ -681,7 +786,9 @@ enum window_state {
 }
 use window_state::*;
 
-static mut CURSOR_STORAGE: [TCursor; 2] = [new!(TCursor), new!(TCursor)];
+// the DECSC cursors, which linkgc must see
+#[no_mangle]
+pub static mut cursorstorage: [TCursor; 2] = [new!(TCursor), new!(TCursor)];
 
 #[repr(C)]
 #[allow(dead_code)]
@@ -868,8 +975,14 @@ pub type Rune = uint32_t;
 pub struct Glyph {
     u: Rune, /* character code */
     mode: c_ushort, /* attribute flags */
+    link: c_ushort, /* hyperlink index, 0 if none */
     fg: uint32_t, /* foreground  */
     bg: uint32_t, /* background  */
+    img: c_ushort, /* image index, 0 if none */
+    imgx: c_ushort, /* cell column within the image */
+    imgy: c_ushort, /* cell row within the image */
+    ul: c_ushort, /* underline style and flags */
+    ulcolor: uint32_t, /* underline color */
 }
 
 #[repr(C)]
@@ -895,13 +1008,28 @@ pub static mut term: Term = Term {
     c: new!(TCursor),
     top: 0,
     bot: 0,
+    left: 0,
+    right: 0,
     mode: 0,
     esc: 0,
     trantbl: [0, 0, 0, 0],
     charset: 0,
     icharset: 0,
+    charsetr: 2,
+    sshift: 0,
+    nrcs: 0,
     numlock: 1,
     tabs: 0 as *mut c_int,
+    cwd: 0 as *mut c_char,
+    images: 0 as *mut c_void,
+    imageslen: 0,
+    graphics: 0 as *mut c_void,
+    graphicslen: 0,
+    kbdflags: [[0; KBD_STACK_SIZ]; 2],
+    kbdtop: [0; 2],
+    modkeys: 0,
+    fmtkeys: config::formatotherkeys,
+    lastc: 0,
 };
 
 #[repr(C)]
@@ -919,18 +1047,43 @@ pub struct Term {
     c: TCursor,
     top: c_int,
     bot: c_int,
+    left: c_int,
+    right: c_int,
     mode: c_int,
     esc: c_int,
     trantbl: [c_char; 4],
     charset: c_int,
     icharset: c_int,
+    charsetr: c_int,
+    sshift: c_int,
+    nrcs: c_int,
     numlock: c_int,
     tabs: *mut c_int,
+    cwd: *mut c_char, /* working directory reported by OSC 7 */
+    images: *mut c_void, /* placed images, referenced by glyphs */
+    imageslen: c_int,
+    graphics: *mut c_void, /* kitty images transmitted by id */
+    graphicslen: c_int,
+    kbdflags: [[c_uint; KBD_STACK_SIZ]; 2], /* kitty keyboard flags per screen */
+    kbdtop: [c_int; 2],
+    modkeys: c_int, /* modifyOtherKeys level */
+    fmtkeys: c_int, /* formatOtherKeys */
+    lastc: Rune, /* last printed char outside of sequence, 0 if control */
 }
 
 #[no_mangle]
 pub static mut cmdfd: c_int = 0;
 
+/* the hyperlink under the mouse pointer, 0 if none */
+#[no_mangle]
+pub static mut hoverlink: c_ushort = 0;
+
+/* when the synchronized update began, and how many have ended, see tsetmode */
+#[no_mangle]
+pub static mut syncstart: libc::timespec = new!(libc::timespec);
+#[no_mangle]
+pub static mut syncended: c_uint = 0;
+
 #[no_mangle]
 pub static mut usedfontsize: c_double = 0.0;
 
@@ -949,15 +1102,15 @@ pub static mut defaultfontsize: c_double = 0.0;
 pub unsafe extern "C" fn tsavecursor() {
     let alt = is_set_on!(MODE_ALTSCREEN, term.mode, c_int) as usize;
 
-    CURSOR_STORAGE[alt] = term.c.clone();
+    cursorstorage[alt] = term.c.clone();
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn tloadcursor() {
     let alt = is_set_on!(MODE_ALTSCREEN, term.mode, c_int) as usize;
 
-    term.c = CURSOR_STORAGE[alt];
-    tmoveto(CURSOR_STORAGE[alt].x, CURSOR_STORAGE[alt].y);
+    term.c = cursorstorage[alt];
+    tmoveto(cursorstorage[alt].x, cursorstorage[alt].y);
 }
 
 #[no_mangle]
@@ -1116,9 +1269,18 @@ pub unsafe extern "C" fn treset() {
 
     term.top = 0;
     term.bot = term.row - 1;
+    term.left = 0;
+    term.right = term.col - 1;
     term.mode = MODE_WRAP as c_int;
     term.trantbl = [CS_USA as c_char, CS_USA as c_char, CS_USA as c_char, CS_USA as c_char];
     term.charset = 0;
+    term.charsetr = 2;
+    term.sshift = 0;
+    term.nrcs = 0;
+    term.kbdflags = [[0; KBD_STACK_SIZ]; 2];
+    term.kbdtop = [0; 2];
+    term.modkeys = 0;
+    term.fmtkeys = config::formatotherkeys;
 
     for _ in 0..2 {
         tmoveto(0, 0);
@@ -1130,18 +1292,24 @@ pub unsafe extern "C" fn treset() {
 
 #[no_mangle]
 pub unsafe extern "C" fn tmoveto(x: c_int, y: c_int) {
+    let minx;
+    let maxx;
     let miny;
     let maxy;
 
     if term.c.state & (CURSOR_ORIGIN as c_char) != 0 {
+        minx = term.left;
+        maxx = term.right;
         miny = term.top;
         maxy = term.bot;
     } else {
+        minx = 0;
+        maxx = term.col - 1;
         miny = 0;
         maxy = term.row - 1;
     }
     term.c.state &= !(CURSOR_WRAPNEXT as c_char);
-    term.c.x = limit!(x, 0, term.col - 1);
+    term.c.x = limit!(x, minx, maxx);
     term.c.y = limit!(y, miny, maxy);
 }
 
@@ -1247,6 +1415,9 @@ pub unsafe extern "C" fn draw() {
             if ena_sel && selected(x, y) != 0 {
                 new.mode ^= ATTR_REVERSE as u16;
             }
+            if new.link != 0 && new.link == hoverlink {
+                new.mode |= ATTR_UNDERLINE as u16;
+            }
             if i > 0 && attr_cmp!(base, new) {
                 xdrawglyphfontspecs(specs, base, i, ox, y);
                 specs = specs.offset(i as isize);
@@ -1263,6 +1434,12 @@ pub unsafe extern "C" fn draw() {
         if i > 0 {
             xdrawglyphfontspecs(specs, base, i, ox, y);
         }
+        for x in 0..term.col {
+            let g = *term_line(y).offset(x as isize);
+            if g.img != 0 {
+                xdrawimage(g, x, y);
+            }
+        }
         y += 1;
     }
 
@@ -1290,6 +1467,18 @@ unsafe fn xdrawglyph(g: Glyph, x: c_int, y: c_int) {
                                        x,
                                        y);
     xdrawglyphfontspecs(&mut spec as *mut xft::XftGlyphFontSpec, g, numspecs, x, y);
+    if g.img != 0 {
+        xdrawimage(g, x, y);
+    }
+}
+
+/* the blinking styles only blink while focused, like xterm */
+unsafe fn cursorblinks() -> bool {
+    config::cursorblinktimeout != 0 && is_set_on!(WIN_FOCUSED, xw.state, c_char) &&
+    match xw.cursor {
+        0 | 1 | 3 | 5 => true,
+        _ => false,
+    }
 }
 
 static mut oldx: c_int = 0;
@@ -1298,8 +1487,14 @@ unsafe fn xdrawcursor() {
     let mut g = Glyph {
         u: b' ' as uint32_t, /* character code */
         mode: ATTR_NULL as c_ushort, /* attribute flags */
+        link: 0, /* hyperlink index */
         fg: config::defaultbg, /* foreground  */
         bg: defaultcs, /* background  */
+        img: 0, /* image index */
+        imgx: 0,
+        imgy: 0,
+        ul: 0, /* underline style */
+        ulcolor: 0,
     };
     let ena_sel = get_ena_sel();
 
@@ -1349,7 +1544,8 @@ unsafe fn xdrawcursor() {
     }
 
 
-    if is_set_on!(MODE_HIDE, term.mode, c_int) {
+    if is_set_on!(MODE_HIDE, term.mode, c_int) ||
+       (cursorblinks() && is_set_on!(MODE_CBLINK, term.mode, c_int)) {
         return;
     }
 
@@ -1572,9 +1768,11 @@ unsafe fn xinit(opt_embed: Option<String>) {
     xw.attrs.border_pixel = dc.col[config::defaultbg as usize].pixel;
     xw.attrs.bit_gravity = xlib::NorthWestGravity;
     xw.attrs.event_mask =
-        xlib::FocusChangeMask | xlib::KeyPressMask | xlib::ExposureMask |
+        xlib::FocusChangeMask | xlib::KeyPressMask | xlib::KeyReleaseMask |
+        xlib::ExposureMask |
         xlib::VisibilityChangeMask | xlib::StructureNotifyMask |
-        xlib::ButtonMotionMask | xlib::ButtonPressMask | xlib::ButtonReleaseMask;
+        xlib::ButtonMotionMask | xlib::ButtonPressMask | xlib::ButtonReleaseMask |
+        xlib::PointerMotionMask /* for hyperlink hovering */;
     xw.attrs.colormap = xw.cmap;
 
     let parent;
@@ -1660,6 +1858,9 @@ unsafe fn xinit(opt_embed: Option<String>) {
         die!("XCreateIC failed. Could not obtain input method.\n");
     }
 
+    /* auto repeat without releases in between, for kitty key event types */
+    xlib::XkbSetDetectableAutoRepeat(xw.dpy, 1, 0 as *mut c_int);
+
     xw.xembed = xlib::XInternAtom(xw.dpy, CString::new("_XEMBED").unwrap().as_ptr(), 0);
     xw.wmdeletewin = xlib::XInternAtom(xw.dpy,
                                        CString::new("WM_DELETE_WINDOW").unwrap().as_ptr(),
@@ -1930,6 +2131,8 @@ unsafe fn tsetdirtattr(attr: c_int) {
 }
 
 
+static mut lastcursorblink: libc::timespec = new!(libc::timespec);
+
 unsafe fn run(mut ev: xlib::XEvent) {
     let xfd = xlib::XConnectionNumber(xw.dpy);
     let mut xev;
@@ -1940,10 +2143,12 @@ unsafe fn run(mut ev: xlib::XEvent) {
     let mut now = new!(libc::timespec);
     let mut last = new!(libc::timespec);
     let mut lastblink;
+    let mut syncdrawn = 0;
     let mut rfd = mem::zeroed();
 
     clock_gettime(CLOCK_MONOTONIC, &mut last as *mut libc::timespec);
     lastblink = last;
+    lastcursorblink = last;
 
     loop {
         xev = actionfps;
@@ -1992,11 +2197,30 @@ unsafe fn run(mut ev: xlib::XEvent) {
             dodraw = true;
         }
 
+        if cursorblinks() && time_diff!(now, lastcursorblink) > config::cursorblinktimeout {
+            term.mode ^= MODE_CBLINK as c_int;
+            lastcursorblink = now;
+            dodraw = true;
+        }
+
         if time_diff!(now, last) > 1000 / (if xev != 0 { xfps } else { actionfps as c_long }) {
             dodraw = true;
             last = now;
         }
 
+        /*
+         * hold frames back during a synchronized update, up to synctimeout,
+         * but always show one that ended, even if the next one has begun
+         */
+        let synced = if syncended != syncdrawn {
+            syncdrawn = syncended;
+            dodraw = true;
+            false
+        } else {
+            is_set_on!(MODE_SYNC, term.mode, c_int) &&
+            time_diff!(now, syncstart) < config::synctimeout
+        };
+
         if dodraw {
             while xlib::XPending(xw.dpy) != 0 {
                 xlib::XNextEvent(xw.dpy, &mut ev as *mut xlib::XEvent);
@@ -2006,17 +2230,35 @@ unsafe fn run(mut ev: xlib::XEvent) {
                 call_handler(ev);
             }
 
-            draw();
+            if !synced {
+                draw();
+            }
             xlib::XFlush(xw.dpy);
 
             if !FD_ISSET(cmdfd, &mut rfd as *mut fd_set) &&
                !FD_ISSET(xfd, &mut rfd as *mut fd_set) {
+                /* sleep until whichever blink comes next, if any */
+                let mut untilblink: Option<c_long> = None;
                 if blinkset != 0 {
-                    if time_diff!(now, lastblink) > blinktimeout {
+                    untilblink = Some(blinktimeout - time_diff!(now, lastblink));
+                }
+                if cursorblinks() {
+                    let untilcursor = config::cursorblinktimeout -
+                                      time_diff!(now, lastcursorblink);
+                    untilblink = Some(untilblink.map_or(untilcursor,
+                                                        |ms| std::cmp::min(ms, untilcursor)));
+                }
+                if synced {
+                    let untilsync = config::synctimeout - time_diff!(now, syncstart);
+                    untilblink = Some(untilblink.map_or(untilsync,
+                                                        |ms| std::cmp::min(ms, untilsync)));
+                }
+
+                if let Some(ms) = untilblink {
+                    if ms < 0 {
                         drawtimeout.tv_nsec = 1000;
                     } else {
-                        drawtimeout.tv_nsec =
-                            (1_000_000 * (blinktimeout - time_diff!(now, lastblink))) as c_long;
+                        drawtimeout.tv_nsec = (1_000_000 * ms) as c_long;
                     }
                     drawtimeout.tv_sec = drawtimeout.tv_nsec / 1_000_000_000;
                     drawtimeout.tv_nsec %= 1_000_000_000;
@@ -2033,6 +2275,9 @@ unsafe fn call_handler(mut ev: xlib::XEvent) {
         xlib::KeyPress => {
             kpress(&mut ev as *mut xlib::XEvent);
         }
+        xlib::KeyRelease => {
+            krelease(&mut ev as *mut xlib::XEvent);
+        }
         xlib::ClientMessage => {
             cmessage(&mut ev as *mut xlib::XEvent);
         }
@@ -2094,6 +2339,10 @@ unsafe fn kpress(ev: *mut xlib::XEvent) {
         return;
     }
 
+    /* restart the blink so the cursor stays visible while typing */
+    clock_gettime(CLOCK_MONOTONIC, &mut lastcursorblink as *mut libc::timespec);
+    term.mode &= !(MODE_CBLINK as c_int);
+
     //TODO there's probably a way to do this that doesn't
     // require 32 0's in a row, but this works for now
     let bufffer = &mut [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -2110,19 +2359,36 @@ unsafe fn kpress(ev: *mut xlib::XEvent) {
                                                 &mut status as *mut c_int) as
                           size_t;
 
+    let held = &mut keyheld[(*e).keycode as usize & 0xff];
+    let event = if *held { 2 } else { 1 };
+    *held = true;
+
     /* 1. shortcuts */
     if handle_shortcut(ksym, (*e).state) {
         return;
     }
 
-    /* 2. custom keys from config.h */
+    /* 2. kitty keyboard protocol, when the application asked for it */
+    if kbdflags() != 0 && kittykey(e, ksym, event) {
+        return;
+    }
+
+    /*
+     * 3. xterm's modifyOtherKeys, ahead of config.h since its XK_ANY_MOD
+     * entries, like the one for Return, would match any modifiers
+     */
+    if term.modkeys != 0 && modotherkey(ksym, (*e).state) {
+        return;
+    }
+
+    /* 4. custom keys from config.h */
     let customkey = kmap(ksym, (*e).state);
     if !customkey.is_null() {
         ttysend(customkey, strlen(customkey));
         return;
     }
 
-    /* 3. composed string from input method */
+    /* 5. composed string from input method */
 
     if len == 0 {
         return;
@@ -2143,9 +2409,265 @@ unsafe fn kpress(ev: *mut xlib::XEvent) {
     ttysend(buf, len);
 }
 
+/* kitty keyboard protocol flags */
+const KBD_DISAMBIGUATE: c_uint = 1;
+const KBD_EVENTS: c_uint = 2;
+const KBD_ALTERNATES: c_uint = 4;
+const KBD_ALLKEYS: c_uint = 8;
+
+/* keys that are down, to tell auto repeats from presses */
+static mut keyheld: [bool; 256] = [false; 256];
+
+unsafe fn kbdflags() -> c_uint {
+    let alt = is_set_on!(MODE_ALTSCREEN, term.mode, c_int) as usize;
+    term.kbdflags[alt][term.kbdtop[alt] as usize]
+}
+
+/* the code and final byte kitty uses for keys that don't produce text */
+fn kittyfunc(ksym: xlib::KeySym) -> Option<(c_uint, u8)> {
+    Some(match ksym as c_uint {
+        XK_Escape => (27, b'u'),
+        XK_Return => (13, b'u'),
+        XK_Tab | XK_ISO_Left_Tab => (9, b'u'),
+        XK_BackSpace => (127, b'u'),
+        XK_Insert => (2, b'~'),
+        XK_Delete => (3, b'~'),
+        XK_Left => (1, b'D'),
+        XK_Right => (1, b'C'),
+        XK_Up => (1, b'A'),
+        XK_Down => (1, b'B'),
+        XK_Prior => (5, b'~'),
+        XK_Next => (6, b'~'),
+        XK_Home => (1, b'H'),
+        XK_End => (1, b'F'),
+        XK_F1 => (1, b'P'),
+        XK_F2 => (1, b'Q'),
+        XK_F3 => (13, b'~'),
+        XK_F4 => (1, b'S'),
+        XK_F5 => (15, b'~'),
+        XK_F6 => (17, b'~'),
+        XK_F7 => (18, b'~'),
+        XK_F8 => (19, b'~'),
+        XK_F9 => (20, b'~'),
+        XK_F10 => (21, b'~'),
+        XK_F11 => (23, b'~'),
+        XK_F12 => (24, b'~'),
+        k if k >= XK_F13 && k <= XK_F35 => (57376 + k - XK_F13, b'u'),
+        XK_Caps_Lock => (57358, b'u'),
+        XK_Scroll_Lock => (57359, b'u'),
+        XK_Num_Lock => (57360, b'u'),
+        XK_Print => (57361, b'u'),
+        XK_Pause => (57362, b'u'),
+        XK_Menu => (57363, b'u'),
+        k if k >= XK_KP_0 && k <= XK_KP_9 => (57399 + k - XK_KP_0, b'u'),
+        XK_KP_Decimal => (57409, b'u'),
+        XK_KP_Divide => (57410, b'u'),
+        XK_KP_Multiply => (57411, b'u'),
+        XK_KP_Subtract => (57412, b'u'),
+        XK_KP_Add => (57413, b'u'),
+        XK_KP_Enter => (57414, b'u'),
+        XK_KP_Equal => (57415, b'u'),
+        XK_KP_Separator => (57416, b'u'),
+        XK_KP_Left => (57417, b'u'),
+        XK_KP_Right => (57418, b'u'),
+        XK_KP_Up => (57419, b'u'),
+        XK_KP_Down => (57420, b'u'),
+        XK_KP_Prior => (57421, b'u'),
+        XK_KP_Next => (57422, b'u'),
+        XK_KP_Home => (57423, b'u'),
+        XK_KP_End => (57424, b'u'),
+        XK_KP_Insert => (57425, b'u'),
+        XK_KP_Delete => (57426, b'u'),
+        XK_KP_Begin => (57427, b'u'),
+        XK_Shift_L => (57441, b'u'),
+        XK_Control_L => (57442, b'u'),
+        XK_Alt_L => (57443, b'u'),
+        XK_Super_L => (57444, b'u'),
+        XK_Hyper_L => (57445, b'u'),
+        XK_Meta_L => (57446, b'u'),
+        XK_Shift_R => (57447, b'u'),
+        XK_Control_R => (57448, b'u'),
+        XK_Alt_R => (57449, b'u'),
+        XK_Super_R => (57450, b'u'),
+        XK_Hyper_R => (57451, b'u'),
+        XK_Meta_R => (57452, b'u'),
+        XK_ISO_Level3_Shift => (57453, b'u'),
+        XK_ISO_Level5_Shift => (57454, b'u'),
+        _ => return None,
+    })
+}
+
+/* the unicode code point of a keysym that produces text */
+fn ksymcode(ksym: xlib::KeySym) -> Option<c_uint> {
+    if ksym >= 0x20 && ksym <= 0x7e || ksym >= 0xa0 && ksym <= 0xff {
+        Some(ksym as c_uint)
+    } else if ksym >= 0x1000000 && ksym <= 0x110ffff {
+        Some((ksym - 0x1000000) as c_uint)
+    } else {
+        None
+    }
+}
+
+/*
+ * Sends the key the kitty keyboard protocol way, event being 1 for a press,
+ * 2 for a repeat and 3 for a release. Returns false when the key should be
+ * sent the legacy way instead.
+ */
+unsafe fn kittykey(e: *mut xlib::XKeyEvent, ksym: xlib::KeySym, event: c_uint) -> bool {
+    let flags = kbdflags();
+    let all = flags & KBD_ALLKEYS != 0;
+    let event = if flags & KBD_EVENTS != 0 { event } else { 1 };
+    let state = (*e).state;
+
+    let mut mods = 0;
+    for &(mask, bit) in [(ShiftMask, 1), (xlib::Mod1Mask, 2), (ControlMask, 4),
+                         (xlib::Mod4Mask, 8), (xlib::LockMask, 64), (xlib::Mod2Mask, 128)]
+        .iter() {
+        if state & mask != 0 {
+            mods |= bit;
+        }
+    }
+    let modified = mods & (2 | 4 | 8) != 0;
+
+    let base = xlib::XLookupKeysym(e, 0);
+    let (code, fin, text) = match kittyfunc(ksym) {
+        Some((code, fin)) => (code, fin, false),
+        None => {
+            match ksymcode(base) {
+                Some(code) => (code, b'u', true),
+                None => return false,
+            }
+        }
+    };
+
+    let entertabbs = fin == b'u' && (code == 13 || code == 9 || code == 127);
+
+    if !all {
+        /* lock and modifier keys are only reported with all the keys */
+        if (code >= 57358 && code <= 57360) || (code >= 57441 && code <= 57454) {
+            return event == 3;
+        }
+        /* enter, tab and backspace releases aren't reported either */
+        if event == 3 && entertabbs {
+            return true;
+        }
+        if event == 1 {
+            if text && !modified {
+                return false;
+            }
+            if entertabbs && mods & (1 | 2 | 4 | 8) == 0 {
+                return false;
+            }
+            if fin != b'u' && !modified {
+                return false;
+            }
+        }
+        if flags & (KBD_DISAMBIGUATE | KBD_EVENTS) == 0 {
+            return false;
+        }
+    }
+
+    let mut s = String::from("\x1b[");
+    if fin == b'u' || fin == b'~' || mods != 0 || event != 1 {
+        s.push_str(&code.to_string());
+    }
+    if flags & KBD_ALTERNATES != 0 && text && mods & 1 != 0 {
+        if let Some(shifted) = ksymcode(ksym) {
+            if shifted != code {
+                s.push_str(&format!(":{}", shifted));
+            }
+        }
+    }
+    if mods != 0 || event != 1 {
+        s.push_str(&format!(";{}", mods + 1));
+        if event != 1 {
+            s.push_str(&format!(":{}", event));
+        }
+    }
+    s.push(fin as char);
+
+    ttysend(s.as_ptr() as *const c_char, s.len());
+    true
+}
+
+/*
+ * Sends modified keys as CSI 27 ; mods ; key ~, or as CSI key ; mods u with
+ * formatOtherKeys. Level 1 only does so for the combinations that have no
+ * usual encoding, level 2 for all of them but shifted printable keys.
+ */
+unsafe fn modotherkey(ksym: xlib::KeySym, state: c_uint) -> bool {
+    let code = match ksym as c_uint {
+        XK_Return => 13,
+        XK_Tab => 9,
+        XK_BackSpace => 127,
+        XK_Escape => 27,
+        _ => {
+            match ksymcode(ksym) {
+                Some(code) => code,
+                None => return false,
+            }
+        }
+    };
+
+    let mut mods = 0;
+    for &(mask, bit) in [(ShiftMask, 1), (xlib::Mod1Mask, 2), (ControlMask, 4),
+                         (xlib::Mod4Mask, 8)]
+        .iter() {
+        if state & mask != 0 {
+            mods |= bit;
+        }
+    }
+    let printable = code >= 0x20 && code != 127;
+
+    let modify = match term.modkeys {
+        1 => {
+            /* control with a key that has a control character is left alone */
+            let ctrlchar = printable && code < 0x80 &&
+                           b"@[\\]^_ abcdefghijklmnopqrstuvwxyz".contains(&(code as u8));
+            mods & 4 != 0 && (mods & 1 != 0 || !ctrlchar)
+        }
+        _ => mods & (2 | 4 | 8) != 0 || (mods & 1 != 0 && !printable),
+    };
+    if !modify {
+        return false;
+    }
+
+    let s = if term.fmtkeys != 0 {
+        format!("\x1b[{};{}u", code, mods + 1)
+    } else {
+        format!("\x1b[27;{};{}~", mods + 1, code)
+    };
+    ttysend(s.as_ptr() as *const c_char, s.len());
+    true
+}
+
+unsafe fn krelease(ev: *mut xlib::XEvent) {
+    let e: *mut xlib::XKeyEvent = &mut xlib::XKeyEvent::from(*ev) as *mut xlib::XKeyEvent;
+
+    keyheld[(*e).keycode as usize & 0xff] = false;
+    if is_set_on!(MODE_KBDLOCK, term.mode, i32) || kbdflags() & KBD_EVENTS == 0 {
+        return;
+    }
+
+    let mut buf = [0 as c_char; 32];
+    let mut ksym: xlib::KeySym = 0;
+    xlib::XLookupString(e,
+                        buf.as_mut_ptr(),
+                        buf.len() as c_int,
+                        &mut ksym as *mut xlib::KeySym,
+                        0 as *mut xlib::XComposeStatus);
+    kittykey(e, ksym, 3);
+}
+
 unsafe fn bpress(ev: *mut xlib::XEvent) {
     let e: *mut xlib::XButtonEvent = &mut xlib::XButtonEvent::from(*ev) as *mut xlib::XButtonEvent;
 
+    if (*e).button == Button1 && ((*e).state & ControlMask) != 0 {
+        if openlink(x2col((*e).x), y2row((*e).y)) {
+            return;
+        }
+    }
+
     if is_set_on!(MODE_MOUSE, term.mode, i32) && ((*e).state & config::forceselmod) == 0 {
         mousereport(ev);
         return;
@@ -2176,10 +2698,15 @@ unsafe fn bpress(ev: *mut xlib::XEvent) {
 
         /*
          * If the user clicks below predefined timeouts specific
-         * snapping behaviour is exposed.
+         * snapping behaviour is exposed. Holding control on a triple
+         * click selects the whole output of a command instead of a line.
          */
         if time_diff!(now, sel.tclick2) <= config::tripleclicktimeout {
-            sel.snap = SNAP_LINE as i32;
+            sel.snap = if ((*e).state & ControlMask) != 0 {
+                SNAP_OUTPUT
+            } else {
+                SNAP_LINE
+            } as i32;
         } else if time_diff!(now, sel.tclick1) <= config::doubleclicktimeout {
             sel.snap = SNAP_WORD as i32;
         } else {
@@ -2196,6 +2723,19 @@ unsafe fn bpress(ev: *mut xlib::XEvent) {
     }
 }
 
+//returns true if there was a hyperlink to open
+unsafe fn openlink(x: c_int, y: c_int) -> bool {
+    let uri = linkuri((*term_line(y).offset(x as isize)).link);
+    if uri.is_null() {
+        return false;
+    }
+
+    let uri = CStr::from_ptr(uri).to_string_lossy().into_owned();
+    spawn(Command::new(config::linkopener).arg(&uri));
+
+    true
+}
+
 unsafe fn x2col(mut x: c_int) -> c_int {
     x -= config::borderpx;
     x /= xw.cw;
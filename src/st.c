@@ -29,6 +29,8 @@
 #include <X11/Xft/Xft.h>
 #include <X11/XKBlib.h>
 #include <fontconfig/fontconfig.h>
+#include <png.h>
+#include <zlib.h>
 #include <wchar.h>
 
 #define Glyph Glyph_
@@ -53,7 +55,16 @@
 #define ESC_BUF_SIZ   (128*UTF_SIZ)
 #define ESC_ARG_SIZ   16
 #define STR_BUF_SIZ   ESC_BUF_SIZ
+#define STR_BUF_MAX   (16*1024*1024)
+#define IMAGE_MAX     4096 /* max image width and height */
+#define KITTY_QUOTA   (320*1024*1024) /* bytes of kitty image data kept */
+#define SIXEL_COLORS  256
 #define STR_ARG_SIZ   ESC_ARG_SIZ
+#define TITLE_STACK_SIZ 10
+#define LINK_BUCKETS  1024
+#define LINK_GC_MIN   256 /* links to have before collecting unused ones */
+#define KBD_STACK_SIZ 8
+#define KBD_SUPPORTED 0xf /* kitty keyboard flags up to report all keys */
 #define XK_ANY_MOD    UINT_MAX
 #define XK_NO_MOD     0
 #define XK_SWITCH_MOD (1<<13)
@@ -70,7 +81,8 @@
 #define ISCONTROL(c)		(ISCONTROLC0(c) || ISCONTROLC1(c))
 #define ISDELIM(u)		(utf8strchr(worddelimiters, u) != NULL)
 #define ATTRCMP(a, b)		((a).mode != (b).mode || (a).fg != (b).fg || \
-				(a).bg != (b).bg)
+				(a).bg != (b).bg || (a).ul != (b).ul || \
+				(a).ulcolor != (b).ulcolor)
 #define TIMEDIFF(t1, t2)	((t1.tv_sec-t2.tv_sec)*1000 + \
 				(t1.tv_nsec-t2.tv_nsec)/1E6)
 #define MODBIT(x, set, bit)	((set) ? ((x) |= (bit)) : ((x) &= ~(bit)))
@@ -85,6 +97,7 @@
 /* transferred macros */
 #define LIMIT(x, a, b)		(x) = (x) < (a) ? (a) : (x) > (b) ? (b) : (x)
 #define IS_SET(flag)		((term.mode & (flag)) != 0)
+#define ISLRMARGIN		(term.left != 0 || term.right != term.col-1)
 
 
 enum glyph_attribute {
@@ -100,7 +113,24 @@ enum glyph_attribute {
 	ATTR_WRAP       = 1 << 8,
 	ATTR_WIDE       = 1 << 9,
 	ATTR_WDUMMY     = 1 << 10,
+	ATTR_PROMPT     = 1 << 11, /* OSC 133 marks, kept by tsetchar */
+	ATTR_INPUT      = 1 << 12,
+	ATTR_OUTPUT     = 1 << 13,
+	ATTR_DONE       = 1 << 14,
+	ATTR_PROTECTED  = 1 << 15, /* DECSCA, skipped by DECSED and DECSEL */
 	ATTR_BOLD_FAINT = ATTR_BOLD | ATTR_FAINT,
+	ATTR_MARKS      = ATTR_PROMPT | ATTR_INPUT | ATTR_OUTPUT | ATTR_DONE,
+};
+
+/* underline style, the low bits of Glyph.ul when ATTR_UNDERLINE is set */
+enum underline_style {
+	UL_SINGLE = 0,
+	UL_DOUBLE = 1,
+	UL_CURLY  = 2,
+	UL_DOTTED = 3,
+	UL_DASHED = 4,
+	UL_STYLE  = 0xff,
+	UL_COLOR  = 1 << 8, /* ulcolor is set, otherwise fg is used */
 };
 
 enum cursor_state {
@@ -131,6 +161,16 @@ enum term_mode {
 	MODE_MOUSEMANY   = 1 << 18,
 	MODE_BRCKTPASTE  = 1 << 19,
 	MODE_PRINT       = 1 << 20,
+	MODE_CBLINK      = 1 << 21, /* cursor blink phase, hidden if set */
+	MODE_SYNC        = 1 << 22, /* synchronized update, don't draw */
+	MODE_MOUSEPIXELS = 1 << 23,
+	MODE_MOUSEURXVT  = 1 << 24,
+	MODE_MOUSEUTF8   = 1 << 25,
+	MODE_LRMM        = 1 << 26,
+	MODE_ALLOWCOLM   = 1 << 27,
+	MODE_132COLS     = 1 << 28,
+	MODE_RECTEXT     = 1 << 29,
+	MODE_VT52        = 1 << 30,
 	MODE_MOUSE       = MODE_MOUSEBTN|MODE_MOUSEMOTION|MODE_MOUSEX10\
 	                  |MODE_MOUSEMANY,
 };
@@ -142,7 +182,18 @@ enum charset {
 	CS_USA,
 	CS_MULTI,
 	CS_GER,
-	CS_FIN
+	CS_FIN,
+	CS_TECH,
+	CS_LATIN1, /* the ISO 8859-1 96 character set */
+	CS_DUT,
+	CS_FRE,
+	CS_FRECAN,
+	CS_ITA,
+	CS_NOR,
+	CS_POR,
+	CS_SPA,
+	CS_SWE,
+	CS_SWI
 };
 
 enum escape_state {
@@ -152,6 +203,7 @@ enum escape_state {
 	ESC_ALTCHARSET = 8,
 	ESC_STR_END    = 16, /* a final string was encountered */
 	ESC_TEST       = 32, /* Enter in test mode */
+	ESC_VT52Y      = 64, /* VT52 ESC Y, reading the row and column */
 };
 
 enum window_state {
@@ -172,7 +224,8 @@ enum selection_type {
 
 enum selection_snap {
 	SNAP_WORD = 1,
-	SNAP_LINE = 2
+	SNAP_LINE = 2,
+	SNAP_OUTPUT = 3
 };
 
 typedef unsigned char uchar;
@@ -188,8 +241,14 @@ typedef XftColor Color;
 typedef struct {
 	Rune u;           /* character code */
 	ushort mode;      /* attribute flags */
+	ushort link;      /* hyperlink index, 0 if none */
 	uint32_t fg;      /* foreground  */
 	uint32_t bg;      /* background  */
+	ushort img;       /* image index, 0 if none */
+	ushort imgx;      /* cell column within the image */
+	ushort imgy;      /* cell row within the image */
+	ushort ul;        /* underline style and flags */
+	uint32_t ulcolor; /* underline color */
 } Glyph;
 
 typedef Glyph *Line;
@@ -206,8 +265,9 @@ typedef struct {
 typedef struct {
 	char buf[ESC_BUF_SIZ]; /* raw string */
 	int len;               /* raw string length */
-	char priv;
+	char priv;             /* private marker: '?', '<', '=' or '>' */
 	int arg[ESC_ARG_SIZ];
+	char sub[ESC_ARG_SIZ]; /* arg is a ':' subparameter of the one before */
 	int narg;              /* nb of args */
 	char mode[2];
 } CSIEscape;
@@ -216,8 +276,9 @@ typedef struct {
 /* ESC type [[ [<priv>] <arg> [;]] <mode>] ESC '\' */
 typedef struct {
 	char type;             /* ESC type ... */
-	char buf[STR_BUF_SIZ]; /* raw string */
-	int len;               /* raw string length */
+	char *buf;             /* raw string, grown up to STR_BUF_MAX */
+	size_t siz;            /* allocated size */
+	size_t len;            /* raw string length */
 	char *args[STR_ARG_SIZ];
 	int narg;              /* nb of args */
 } STREscape;
@@ -278,6 +339,40 @@ typedef struct {
 	struct timespec tclick2;
 } Selection;
 
+/* OSC 8 hyperlink, interned in the links table */
+typedef struct {
+	char *id;      /* NULL if the entry is free */
+	char *uri;
+	ushort next;   /* index + 1 of the next link in its bucket or free list */
+} Link;
+
+/* Graphics placed on the grid, referenced by glyphs */
+typedef struct {
+	int w, h;       /* size in pixels */
+	int cw, ch;     /* cell size when placed */
+	uint id, pid;   /* kitty image and placement ids, 0 for sixel */
+	Pixmap pix;
+	Picture pic;    /* 0 if the slot is free */
+} Image;
+
+/* Kitty graphics image data, kept until deleted so it can be placed */
+typedef struct {
+	uint id;        /* i key */
+	uint num;       /* I key, 0 if none */
+	int w, h;
+	uint32_t *pixels; /* premultiplied ARGB */
+} Graphic;
+
+/* Kitty graphics command, the keys of its first chunk and its payload */
+typedef struct {
+	char a, t, o, d; /* action, medium, compression, what to delete */
+	uint f, i, I, p, q, m, C;
+	int s, v, x, y, w, h, c, r;
+	size_t S, O;     /* file read size and offset */
+	char *data;      /* base64 payload of all the chunks */
+	size_t len;
+} KittyCmd;
+
 typedef union {
 	int i;
 	uint ui;
@@ -290,6 +385,8 @@ void c_clipcopy(const Arg *);
 void c_clippaste(const Arg *);
 void c_kscrolldown(int n);
 void c_kscrollup(int n);
+void c_kpromptdown(int n);
+void c_kpromptup(int n);
 void c_numlock(const Arg *);
 void c_selpaste(const Arg *);
 void c_xzoom(float f);
@@ -317,13 +414,28 @@ typedef struct {
 	TCursor c;    /* cursor */
 	int top;      /* top    scroll limit */
 	int bot;      /* bottom scroll limit */
+	int left;     /* left   scroll limit */
+	int right;    /* right  scroll limit */
 	int mode;     /* terminal mode flags */
 	int esc;      /* escape state flags */
 	char trantbl[4]; /* charset table translation */
 	int charset;  /* current charset */
 	int icharset; /* selected charset for sequence */
+	int charsetr; /* charset locked into GR, state only as input is UTF-8 */
+	int sshift;   /* single shifted charset for the next char, 0 if none */
+	int nrcs;     /* DECNRCM, national replacement sets are used */
 	int c_numlock; /* lock numbers in keyboard */
 	int *tabs;
+	char *cwd;    /* working directory reported by OSC 7 */
+	Image *images; /* placed images, referenced by glyphs */
+	int imageslen;
+	Graphic *graphics; /* kitty images transmitted by id */
+	int graphicslen;
+	uint kbdflags[2][KBD_STACK_SIZ]; /* kitty keyboard flags per screen */
+	int kbdtop[2];
+	int modkeys;  /* modifyOtherKeys level */
+	int fmtkeys;  /* formatOtherKeys, CSI 27 ; m ; k ~ if 0, CSI k ; m u if 1 */
+	Rune lastc;   /* last printed char outside of sequence, 0 if control */
 } Term;
 
 /* Font structure */
@@ -361,10 +473,48 @@ static void csihandle(void);
 static void csiparse(void);
 static void csireset(void);
 static int eschandle(uchar);
+static int vt52handle(uchar);
 static void strdump(void);
 static void strhandle(void);
 static void strparse(void);
 static void strreset(void);
+static void osc52handle(char *, char *);
+static void osc7handle(char *);
+static void oscsetcolor(int, char *);
+static void oscdyncolor(int, char *);
+static void osccolorreply(const char *, int);
+static void osc8handle(char *, char *);
+static void osc133handle(char);
+static ushort linkintern(char *, char *);
+static uint linkhash(char *, char *);
+static void linkgc(void);
+char *linkuri(ushort);
+static void linkhover(int, int);
+static void dcshandle(void);
+static void sixelhandle(char *, char *);
+static int sixelargs(char **, int *, int);
+static uint32_t sixelhls(int, int, int);
+static uint32_t *sixelgrow(uint32_t *, int *, int *, int, int);
+static void xtsmgraphics(int, int);
+static ushort imagenew(uint32_t *, int, int, int);
+static void imagegc(void);
+static void tputimage(ushort);
+void xdrawimage(Glyph, int, int);
+static void apchandle(void);
+static void kittyhandle(char *);
+static void kittyparse(char *, KittyCmd *);
+static void kittyrun(KittyCmd *);
+static void kittyreply(KittyCmd *, const char *);
+static Graphic *kittyfind(uint, uint);
+static void kittyquota(size_t);
+static uint32_t *kittyload(KittyCmd *, int *, int *, char **);
+static char *kittyread(KittyCmd *, size_t *, char **);
+static uint32_t *kittypng(uchar *, size_t, int *, int *, char **);
+static uint32_t kittyargb(uchar, uchar, uchar, uchar);
+static int kittytemp(const char *);
+static int kittyplace(KittyCmd *, Graphic *, char **);
+static void kittyerase(uint, uint);
+static void kittydelete(KittyCmd *);
 
 static void tprinter(char *, size_t);
 static void tdumpsel(void);
@@ -376,6 +526,8 @@ static void tdeleteline(int);
 static void tinsertblank(int);
 static void tinsertblankline(int);
 static int tlinelen(int);
+static int tlinemark(Line, int);
+static Line tvline(int);
 extern void tmoveto(int, int);
 static void tmoveato(int, int);
 static void tnewline(int);
@@ -385,20 +537,39 @@ extern void treset(void);
 void tresize(int, int);
 static void tscrollup(int, int, int);
 static void tscrolldown(int, int, int);
-static void tsetattr(int *, int);
+static void tsetattr(int *, char *, int);
 static void tsetchar(Rune, Glyph *, int, int);
 static void tsetscroll(int, int);
+static void tsetmargins(int, int);
+static void tdeccolm(int);
+static int trect(int *, int *, int *, int *, int *);
+static void tsplitwide(int, int, int);
+static void tclear(int, int, int, int, int);
+static void terase(int, int, int, int);
+static void tselectiveclear(int, int, int, int);
+static void tfillrect(Rune, int *);
+static void terasrect(int *);
+static void tcopyrect(int *);
+static void tattrrect(int *, int, int);
+static void tsettitle(char *);
+static int twinopallowed(int);
+static void twinops(int *);
+static void tscrollmargins(int, int);
 extern void tswapscreen(void);
 void tsetdirt(int, int);
 static void tsetmode(int, int, int *, int);
+static void tkbdflags(char, int, int);
+static int tgetmode(int, int);
 extern void tfulldirt(void);
 static void techo(Rune);
 static void tcontrolcode(uchar );
 static void tdectest(char );
-static int32_t tdefcolor(int *, int *, int);
-static void tdeftran(char);
+static int32_t tdefcolor(int *, char *, int *, int);
+static int tdeftran(char);
+static Rune ttranslate(Rune);
 static inline int match(uint, uint);
 void ttynew(void);
+pid_t shellpid(void);
 size_t ttyread(void);
 void ttyresize(void);
 void ttysend(char *, size_t);
@@ -409,18 +580,20 @@ static inline ushort sixd_to_16bit(int);
 int xmakeglyphfontspecs(XftGlyphFontSpec *, const Glyph *, int, int, int);
  void xdrawglyphfontspecs(const XftGlyphFontSpec *, Glyph, int, int, int);
 static void xdrawglyph(Glyph, int, int);
+static void xdrawunderline(Color *, int, int, int, int);
+static void xdrawdashes(Color *, int, int, int, int, int);
 static void xhints(void);
 static void xclear(int, int, int, int);
 static void xinit(void);
 void xloadcols(void);
 int xloadcolor(int, const char*, Color*);
 static int xsetcolorname(int, const char *);
+static void xcolorchanged(int);
 static int xgeommasktogravity(int);
 int xloadfont(Font *, FcPattern *);
 void loadfonts(double);
 extern void xsettitle(char *);
 extern void xresettitle(void);
-static void xsetpointermotion(int);
 static void xseturgency(int);
 static void xsetsel(char *, Time);
 static void xunloadfont(Font *);
@@ -431,6 +604,7 @@ void expose(XEvent *);
 void visibility(XEvent *);
 void unmap(XEvent *);
 char *kmap(KeySym, uint);
+static char *vt52key(KeySym);
 void cmessage(XEvent *);
 void cresize(int, int);
 void resize(XEvent *);
@@ -448,6 +622,7 @@ static char *getsel(void);
 static void selcopy(Time);
 static void selscroll(int, int);
 static void selsnap(int *, int *, int);
+static int selsnapoutput(int *, int *, int);
 static int x2col(int);
 static int y2row(int);
 static void getbuttoninfo(XEvent *);
@@ -460,6 +635,9 @@ static char utf8encodebyte(Rune, size_t);
 static char *utf8strchr(char *s, Rune u);
 static size_t utf8validate(Rune *, size_t);
 
+static char *base64dec(const char *, size_t *);
+static char *base64enc(const char *);
+
 static ssize_t xwrite(int, const char *, size_t);
 static void *xmalloc(size_t);
 static void *xrealloc(void *, size_t);
@@ -490,6 +668,18 @@ static char *opt_io    = NULL;
 static char *opt_line  = NULL;
 static char *opt_name  = NULL;
 static int oldbutton   = 3; /* button event on startup: 3 = release */
+static Link *links     = NULL;
+static int linkslen    = 0;
+static int linksgc     = LINK_GC_MIN; /* linkslen to collect at */
+static ushort linksfree = 0;
+static ushort linksbucket[LINK_BUCKETS];
+static char *title     = NULL; /* set by the program, NULL for the default */
+static char *titlestack[TITLE_STACK_SIZ];
+static int titletop    = 0;
+extern ushort hoverlink;
+extern TCursor cursorstorage[2];
+extern struct timespec syncstart;
+extern uint syncended;
 
 extern double usedfontsize;
 extern double defaultfontsize;
@@ -499,6 +689,9 @@ static uchar utfmask[UTF_SIZ + 1] = {0xC0, 0x80, 0xE0, 0xF0, 0xF8};
 static Rune utfmin[UTF_SIZ + 1] = {       0,    0,  0x80,  0x800,  0x10000};
 static Rune utfmax[UTF_SIZ + 1] = {0x10FFFF, 0x7F, 0x7FF, 0xFFFF, 0x10FFFF};
 
+static const char base64chars[] =
+	"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
 /* Font Ring Cache */
 enum {
 	FRC_NORMAL,
@@ -650,6 +843,60 @@ utf8validate(Rune *u, size_t i)
 	return i;
 }
 
+char *
+base64dec(const char *src, size_t *len)
+{
+	char *result, *dst;
+	const char *p;
+	uint acc = 0;
+	int bits = 0;
+
+	dst = result = xmalloc(strlen(src) / 4 * 3 + 4);
+	for (; *src && *src != '='; src++) {
+		if (isspace((uchar)*src))
+			continue;
+		if (!(p = strchr(base64chars, *src))) {
+			free(result);
+			return NULL;
+		}
+		acc = (acc << 6) | (p - base64chars);
+		bits += 6;
+		if (bits >= 8) {
+			bits -= 8;
+			*dst++ = (acc >> bits) & 0xff;
+		}
+	}
+	*dst = '\0';
+	if (len)
+		*len = dst - result;
+
+	return result;
+}
+
+char *
+base64enc(const char *src)
+{
+	size_t i, len = strlen(src);
+	char *result, *dst;
+	uint acc;
+
+	dst = result = xmalloc(DIVCEIL(len, 3) * 4 + 1);
+	for (i = 0; i < len; i += 3) {
+		acc = (uchar)src[i] << 16;
+		if (i + 1 < len)
+			acc |= (uchar)src[i + 1] << 8;
+		if (i + 2 < len)
+			acc |= (uchar)src[i + 2];
+		*dst++ = base64chars[(acc >> 18) & 0x3f];
+		*dst++ = base64chars[(acc >> 12) & 0x3f];
+		*dst++ = (i + 1 < len) ? base64chars[(acc >> 6) & 0x3f] : '=';
+		*dst++ = (i + 2 < len) ? base64chars[acc & 0x3f] : '=';
+	}
+	*dst = '\0';
+
+	return result;
+}
+
 int
 x2col(int x)
 {
@@ -682,6 +929,34 @@ tlinelen(int y)
 	return i;
 }
 
+/*
+ * Returns the column of the first glyph of the line with any of the marks
+ * in mark set, or -1 if there is none.
+ */
+int
+tlinemark(Line line, int mark)
+{
+	int x;
+
+	for (x = 0; x < term.col; x++) {
+		if (line[x].mode & mark)
+			return x;
+	}
+
+	return -1;
+}
+
+/*
+ * Lines counted from the top of the screen, ignoring the scroll back, so
+ * -1 is the newest history line and -histsize the oldest.
+ */
+Line
+tvline(int v)
+{
+	return v < 0 ? term.hist[(term.histi + v + 1 + histsize) % histsize]
+	             : term.line[v];
+}
+
 void
 selnormalize(void)
 {
@@ -772,6 +1047,10 @@ selsnap(int *x, int *y, int direction)
 			prevdelim = delim;
 		}
 		break;
+	case SNAP_OUTPUT:
+		if (selsnapoutput(x, y, direction))
+			break;
+		/* FALLTHROUGH */
 	case SNAP_LINE:
 		/*
 		 * Snap around if the the previous line or the current one
@@ -798,6 +1077,63 @@ selsnap(int *x, int *y, int direction)
 	}
 }
 
+/*
+ * Snaps to the output of the command around y, which runs from its OSC 133
+ * C mark up to the D or A mark of whatever comes next. Returns 0 if y
+ * isn't in a command's output.
+ */
+int
+selsnapoutput(int *x, int *y, int direction)
+{
+	int sx, sy, ex, ey, mx;
+
+	for (sy = *y; sy >= 0; sy--) {
+		if ((sx = tlinemark(TLINE(sy), ATTR_OUTPUT)) >= 0)
+			break;
+		if (tlinemark(TLINE(sy), ATTR_PROMPT|ATTR_DONE) >= 0)
+			return 0;
+	}
+	if (sy < 0)
+		return 0;
+
+	for (ey = sy, mx = sx; ey < term.row; ey++, mx = 0) {
+		for (; mx < term.col; mx++) {
+			if (TLINE(ey)[mx].mode & (ATTR_PROMPT|ATTR_DONE))
+				break;
+		}
+		if (mx < term.col)
+			break;
+	}
+	if (ey == term.row) {
+		/* the command is still running */
+		ex = term.col - 1;
+		ey = term.row - 1;
+	} else if (mx > 0) {
+		ex = mx - 1;
+	} else {
+		ex = term.col - 1;
+		ey--;
+	}
+
+	/* an empty output has its C and D marks in the same cell */
+	if (ey < sy || (ey == sy && ex < sx)) {
+		ex = sx;
+		ey = sy;
+	}
+	if (ey < *y || (ey == *y && ex < *x))
+		return 0;
+
+	if (direction < 0) {
+		*x = sx;
+		*y = sy;
+	} else {
+		*x = ex;
+		*y = ey;
+	}
+
+	return 1;
+}
+
 void
 getbuttoninfo(XEvent *e)
 {
@@ -828,6 +1164,12 @@ mousereport(XEvent *e)
 	char buf[40];
 	static int ox, oy;
 
+	/* SGR-Pixels reports, and so tells apart, every pixel */
+	if (IS_SET(MODE_MOUSEPIXELS)) {
+		x = MAX(e->xbutton.x - borderpx, 0);
+		y = MAX(e->xbutton.y - borderpx, 0);
+	}
+
 	/* from urxvt */
 	if (e->xbutton.type == MotionNotify) {
 		if (x == ox && y == oy)
@@ -842,7 +1184,8 @@ mousereport(XEvent *e)
 		ox = x;
 		oy = y;
 	} else {
-		if (!IS_SET(MODE_MOUSESGR) && e->xbutton.type == ButtonRelease) {
+		if (!IS_SET(MODE_MOUSESGR|MODE_MOUSEPIXELS) &&
+		    e->xbutton.type == ButtonRelease) {
 			button = 3;
 		} else {
 			button -= Button1;
@@ -869,10 +1212,20 @@ mousereport(XEvent *e)
 			+ ((state & ControlMask) ? 16 : 0);
 	}
 
-	if (IS_SET(MODE_MOUSESGR)) {
+	if (IS_SET(MODE_MOUSESGR|MODE_MOUSEPIXELS)) {
 		len = snprintf(buf, sizeof(buf), "\033[<%d;%d;%d%c",
 				button, x+1, y+1,
 				e->xbutton.type == ButtonRelease ? 'm' : 'M');
+	} else if (IS_SET(MODE_MOUSEURXVT)) {
+		len = snprintf(buf, sizeof(buf), "\033[%d;%d;%dM",
+				32+button, x+1, y+1);
+	} else if (IS_SET(MODE_MOUSEUTF8)) {
+		/* coordinates past 95 are sent as two byte UTF-8 */
+		if (32+x+1 > 0x7FF || 32+y+1 > 0x7FF)
+			return;
+		len = snprintf(buf, sizeof(buf), "\033[M%c", 32+button);
+		len += utf8encode(32+x+1, buf+len);
+		len += utf8encode(32+y+1, buf+len);
 	} else if (x < 223 && y < 223) {
 		len = snprintf(buf, sizeof(buf), "\033[M%c%c%c",
 				32+button, 32+x+1, 32+y+1);
@@ -1178,11 +1531,24 @@ brelease(XEvent *e)
 	}
 }
 
+void
+linkhover(int x, int y)
+{
+	ushort link = TLINE(y2row(y))[x2col(x)].link;
+
+	if (link != hoverlink) {
+		hoverlink = link;
+		tfulldirt();
+	}
+}
+
 void
 bmotion(XEvent *e)
 {
 	int oldey, oldex, oldsby, oldsey;
 
+	linkhover(e->xmotion.x, e->xmotion.y);
+
 	if (IS_SET(MODE_MOUSE) && !(e->xbutton.state & forceselmod)) {
 		mousereport(e);
 		return;
@@ -1356,6 +1722,12 @@ ttynew(void)
 	}
 }
 
+pid_t
+shellpid(void)
+{
+	return pid;
+}
+
 size_t
 ttyread(void)
 {
@@ -1516,6 +1888,34 @@ c_kscrollup(int n)
 	}
 }
 
+/* scroll back so the n-th prompt above the top line becomes the top line */
+void
+c_kpromptup(int n)
+{
+	int v;
+
+	for (v = -term.scr - 1; v >= -histsize; v--) {
+		if (tlinemark(tvline(v), ATTR_PROMPT) >= 0 && --n <= 0) {
+			c_kscrollup(-v - term.scr);
+			return;
+		}
+	}
+}
+
+void
+c_kpromptdown(int n)
+{
+	int v;
+
+	for (v = -term.scr + 1; v < 0; v++) {
+		if (tlinemark(tvline(v), ATTR_PROMPT) >= 0 && --n <= 0) {
+			c_kscrolldown(term.scr + v);
+			return;
+		}
+	}
+	c_kscrolldown(term.scr);
+}
+
 void
 tscrolldown(int orig, int n, int copyhist)
 {
@@ -1524,6 +1924,11 @@ tscrolldown(int orig, int n, int copyhist)
 
 	LIMIT(n, 0, term.bot-orig+1);
 
+	if (ISLRMARGIN) {
+		tscrollmargins(orig, -n);
+		return;
+	}
+
 	if (copyhist) {
 		term.histi = (term.histi - 1 + histsize) % histsize;
 		temp = term.hist[term.histi];
@@ -1551,6 +1956,11 @@ tscrollup(int orig, int n, int copyhist)
 
 	LIMIT(n, 0, term.bot-orig+1);
 
+	if (ISLRMARGIN) {
+		tscrollmargins(orig, n);
+		return;
+	}
+
 	if (copyhist) {
 		term.histi = (term.histi + 1) % histsize;
 		temp = term.hist[term.histi];
@@ -1570,6 +1980,38 @@ tscrollup(int orig, int n, int copyhist)
 	selscroll(orig, -n);
 }
 
+/* scrolls only the columns between the left and right margins */
+void
+tscrollmargins(int orig, int n)
+{
+	int i, up = n > 0;
+	size_t size = (term.right - term.left + 1) * sizeof(Glyph);
+
+	if (!up)
+		n = -n;
+
+	/* a selection can't follow only some of its columns */
+	if (sel.ob.x != -1 && sel.ne.y >= orig && sel.nb.y <= term.bot &&
+	    (sel.type != SEL_RECTANGULAR ||
+	     (sel.nb.x <= term.right && sel.ne.x >= term.left)))
+		selclear(NULL);
+
+	if (up) {
+		for (i = orig; i <= term.bot-n; i++) {
+			memcpy(&term.line[i][term.left],
+			       &term.line[i+n][term.left], size);
+		}
+		tclearregion(term.left, term.bot-n+1, term.right, term.bot);
+	} else {
+		for (i = term.bot; i >= orig+n; i--) {
+			memcpy(&term.line[i][term.left],
+			       &term.line[i-n][term.left], size);
+		}
+		tclearregion(term.left, orig, term.right, orig+n-1);
+	}
+	tsetdirt(orig, term.bot);
+}
+
 void
 selscroll(int orig, int n)
 {
@@ -1603,14 +2045,17 @@ selscroll(int orig, int n)
 void
 tnewline(int first_col)
 {
-	int y = term.c.y;
+	int x = term.c.x, y = term.c.y;
 
 	if (y == term.bot) {
-		tscrollup(term.top, 1, 1);
+		if (BETWEEN(x, term.left, term.right))
+			tscrollup(term.top, 1, 1);
 	} else {
 		y++;
 	}
-	tmoveto(first_col ? 0 : term.c.x, y);
+	if (first_col)
+		x = x < term.left ? 0 : term.left;
+	tmoveto(x, y);
 }
 
 void
@@ -1620,10 +2065,8 @@ csiparse(void)
 	long int v;
 
 	csiescseq.narg = 0;
-	if (*p == '?') {
-		csiescseq.priv = 1;
-		p++;
-	}
+	if (*p != '\0' && strchr("?<=>", *p))
+		csiescseq.priv = *p++;
 
 	csiescseq.buf[csiescseq.len] = '\0';
 	while (p < csiescseq.buf+csiescseq.len) {
@@ -1635,9 +2078,9 @@ csiparse(void)
 			v = -1;
 		csiescseq.arg[csiescseq.narg++] = v;
 		p = np;
-		if (*p != ';' || csiescseq.narg == ESC_ARG_SIZ)
+		if ((*p != ';' && *p != ':') || csiescseq.narg == ESC_ARG_SIZ)
 			break;
-		p++;
+		csiescseq.sub[csiescseq.narg] = *p++ == ':';
 	}
 	csiescseq.mode[0] = *p++;
 	csiescseq.mode[1] = (p < csiescseq.buf+csiescseq.len) ? *p : '\0';
@@ -1647,29 +2090,19 @@ csiparse(void)
 void
 tmoveato(int x, int y)
 {
-	tmoveto(x, y + ((term.c.state & CURSOR_ORIGIN) ? term.top: 0));
+	if (term.c.state & CURSOR_ORIGIN) {
+		x += term.left;
+		y += term.top;
+	}
+	tmoveto(x, y);
 }
 
 void
 tsetchar(Rune u, Glyph *attr, int x, int y)
 {
-	static char *vt100_0[62] = { /* 0x41 - 0x7e */
-		"↑", "↓", "→", "←", "█", "▚", "☃", /* A - G */
-		0, 0, 0, 0, 0, 0, 0, 0, /* H - O */
-		0, 0, 0, 0, 0, 0, 0, 0, /* P - W */
-		0, 0, 0, 0, 0, 0, 0, " ", /* X - _ */
-		"◆", "▒", "␉", "␌", "␍", "␊", "°", "±", /* ` - g */
-		"␤", "␋", "┘", "┐", "┌", "└", "┼", "⎺", /* h - o */
-		"⎻", "─", "⎼", "⎽", "├", "┤", "┴", "┬", /* p - w */
-		"│", "≤", "≥", "π", "≠", "£", "·", /* x - ~ */
-	};
+	ushort mark;
 
-	/*
-	 * The table is proudly stolen from rxvt.
-	 */
-	if (term.trantbl[term.charset] == CS_GRAPHIC0 &&
-	   BETWEEN(u, 0x41, 0x7e) && vt100_0[u - 0x41])
-		utf8decode(vt100_0[u - 0x41], &u, UTF_SIZ);
+	u = ttranslate(u);
 
 	if (term.line[y][x].mode & ATTR_WIDE) {
 		if (x+1 < term.col) {
@@ -1681,13 +2114,37 @@ tsetchar(Rune u, Glyph *attr, int x, int y)
 		term.line[y][x-1].mode &= ~ATTR_WIDE;
 	}
 
+	/*
+	 * OSC 133 marks go where the text is about to be written, so they
+	 * survive filling a blank cell but not painting over other text.
+	 */
+	mark = term.line[y][x].mode & ATTR_MARKS;
+	if (term.line[y][x].u != ' ' && term.line[y][x].u != u)
+		mark = 0;
 	term.dirty[y] = 1;
 	term.line[y][x] = *attr;
 	term.line[y][x].u = u;
+	term.line[y][x].mode |= mark;
 }
 
 void
 tclearregion(int x1, int y1, int x2, int y2)
+{
+	tclear(x1, y1, x2, y2, 0);
+}
+
+/*
+ * Erases like tclearregion, but keeps the OSC 133 marks, since shells
+ * erase their line with EL right after marking the prompt.
+ */
+void
+terase(int x1, int y1, int x2, int y2)
+{
+	tclear(x1, y1, x2, y2, ATTR_MARKS);
+}
+
+void
+tclear(int x1, int y1, int x2, int y2, int keep)
 {
 	int x, y, temp;
 	Glyph *gp;
@@ -1710,7 +2167,42 @@ tclearregion(int x1, int y1, int x2, int y2)
 				selclear(NULL);
 			gp->fg = term.c.attr.fg;
 			gp->bg = term.c.attr.bg;
-			gp->mode = 0;
+			gp->mode &= keep;
+			gp->link = 0;
+			gp->img = 0;
+			gp->u = ' ';
+		}
+	}
+}
+
+/* DECSED and DECSEL only erase the characters not protected by DECSCA */
+void
+tselectiveclear(int x1, int y1, int x2, int y2)
+{
+	int x, y, temp;
+	Glyph *gp;
+
+	if (x1 > x2)
+		temp = x1, x1 = x2, x2 = temp;
+	if (y1 > y2)
+		temp = y1, y1 = y2, y2 = temp;
+
+	LIMIT(x1, 0, term.col-1);
+	LIMIT(x2, 0, term.col-1);
+	LIMIT(y1, 0, term.row-1);
+	LIMIT(y2, 0, term.row-1);
+
+	for (y = y1; y <= y2; y++) {
+		for (x = x1; x <= x2; x++) {
+			gp = &term.line[y][x];
+			if (gp->mode & ATTR_PROTECTED)
+				continue;
+			if (selected(x, y))
+				selclear(NULL);
+			term.dirty[y] = 1;
+			gp->mode &= ~(ATTR_WIDE|ATTR_WDUMMY);
+			gp->link = 0;
+			gp->img = 0;
 			gp->u = ' ';
 		}
 	}
@@ -1722,15 +2214,17 @@ tdeletechar(int n)
 	int dst, src, size;
 	Glyph *line;
 
-	LIMIT(n, 0, term.col - term.c.x);
+	if (!BETWEEN(term.c.x, term.left, term.right))
+		return;
+	LIMIT(n, 0, term.right+1 - term.c.x);
 
 	dst = term.c.x;
 	src = term.c.x + n;
-	size = term.col - src;
+	size = term.right+1 - src;
 	line = term.line[term.c.y];
 
 	memmove(&line[dst], &line[src], size * sizeof(Glyph));
-	tclearregion(term.col-n, term.c.y, term.col-1, term.c.y);
+	tclearregion(term.right+1-n, term.c.y, term.right, term.c.y);
 }
 
 void
@@ -1739,11 +2233,13 @@ tinsertblank(int n)
 	int dst, src, size;
 	Glyph *line;
 
-	LIMIT(n, 0, term.col - term.c.x);
+	if (!BETWEEN(term.c.x, term.left, term.right))
+		return;
+	LIMIT(n, 0, term.right+1 - term.c.x);
 
 	dst = term.c.x + n;
 	src = term.c.x;
-	size = term.col - dst;
+	size = term.right+1 - dst;
 	line = term.line[term.c.y];
 
 	memmove(&line[dst], &line[src], size * sizeof(Glyph));
@@ -1753,35 +2249,53 @@ tinsertblank(int n)
 void
 tinsertblankline(int n)
 {
-	if (BETWEEN(term.c.y, term.top, term.bot))
+	if (BETWEEN(term.c.y, term.top, term.bot) &&
+	    BETWEEN(term.c.x, term.left, term.right))
 		tscrolldown(term.c.y, n, 0);
 }
 
 void
 tdeleteline(int n)
 {
-	if (BETWEEN(term.c.y, term.top, term.bot))
+	if (BETWEEN(term.c.y, term.top, term.bot) &&
+	    BETWEEN(term.c.x, term.left, term.right))
 		tscrollup(term.c.y, n, 0);
 }
 
 int32_t
-tdefcolor(int *attr, int *npar, int l)
+tdefcolor(int *attr, char *sub, int *npar, int l)
 {
 	int32_t idx = -1;
 	uint r, g, b;
+	int *p = &attr[*npar + 1], n = l - *npar - 1, grp = 0;
+
+	/*
+	 * The ':' forms come as one group, 38:5:n and 38:2:[cs]:r:g:b with
+	 * an optional, ignored, colorspace id. The ';' forms, 38;5;n and
+	 * 38;2;r;g;b, just use the following parameters.
+	 */
+	if (n > 0 && sub[*npar + 1]) {
+		for (grp = 1; grp < n && sub[*npar + 1 + grp]; grp++)
+			;
+		n = grp;
+	}
 
-	switch (attr[*npar + 1]) {
+	switch (n > 0 ? p[0] : -1) {
 	case 2: /* direct color in RGB space */
-		if (*npar + 4 >= l) {
+		if (grp > 4) {
+			p++;
+			n--;
+		}
+		if (n < 4) {
 			fprintf(stderr,
 				"erresc(38): Incorrect number of parameters (%d)\n",
 				*npar);
 			break;
 		}
-		r = attr[*npar + 2];
-		g = attr[*npar + 3];
-		b = attr[*npar + 4];
-		*npar += 4;
+		r = p[1];
+		g = p[2];
+		b = p[3];
+		*npar += grp ? grp : 4;
 		if (!BETWEEN(r, 0, 255) || !BETWEEN(g, 0, 255) || !BETWEEN(b, 0, 255))
 			fprintf(stderr, "erresc: bad rgb color (%u,%u,%u)\n",
 				r, g, b);
@@ -1789,17 +2303,17 @@ tdefcolor(int *attr, int *npar, int l)
 			idx = TRUECOLOR(r, g, b);
 		break;
 	case 5: /* indexed color */
-		if (*npar + 2 >= l) {
+		if (n < 2) {
 			fprintf(stderr,
 				"erresc(38): Incorrect number of parameters (%d)\n",
 				*npar);
 			break;
 		}
-		*npar += 2;
-		if (!BETWEEN(attr[*npar], 0, 255))
-			fprintf(stderr, "erresc: bad fgcolor %d\n", attr[*npar]);
+		*npar += grp ? grp : 2;
+		if (!BETWEEN(p[1], 0, 255))
+			fprintf(stderr, "erresc: bad fgcolor %d\n", p[1]);
 		else
-			idx = attr[*npar];
+			idx = p[1];
 		break;
 	case 0: /* implemented defined (only foreground) */
 	case 1: /* transparent */
@@ -1815,9 +2329,9 @@ tdefcolor(int *attr, int *npar, int l)
 }
 
 void
-tsetattr(int *attr, int l)
+tsetattr(int *attr, char *sub, int l)
 {
-	int i;
+	int i, style;
 	int32_t idx;
 
 	for (i = 0; i < l; i++) {
@@ -1834,6 +2348,7 @@ tsetattr(int *attr, int l)
 				ATTR_STRUCK     );
 			term.c.attr.fg = defaultfg;
 			term.c.attr.bg = defaultbg;
+			term.c.attr.ul = 0;
 			break;
 		case 1:
 			term.c.attr.mode |= ATTR_BOLD;
@@ -1845,7 +2360,16 @@ tsetattr(int *attr, int l)
 			term.c.attr.mode |= ATTR_ITALIC;
 			break;
 		case 4:
+			/* 4:0 to 4:5 pick the style, 4:0 being no underline */
+			style = (i + 1 < l && sub[i + 1]) ? attr[++i] : 1;
+			if (style == 0) {
+				term.c.attr.mode &= ~ATTR_UNDERLINE;
+				break;
+			}
 			term.c.attr.mode |= ATTR_UNDERLINE;
+			term.c.attr.ul &= ~UL_STYLE;
+			if (BETWEEN(style, 2, 5))
+				term.c.attr.ul |= style - 1;
 			break;
 		case 5: /* slow blink */
 			/* FALLTHROUGH */
@@ -1861,6 +2385,10 @@ tsetattr(int *attr, int l)
 		case 9:
 			term.c.attr.mode |= ATTR_STRUCK;
 			break;
+		case 21:
+			term.c.attr.mode |= ATTR_UNDERLINE;
+			term.c.attr.ul = (term.c.attr.ul & ~UL_STYLE) | UL_DOUBLE;
+			break;
 		case 22:
 			term.c.attr.mode &= ~(ATTR_BOLD | ATTR_FAINT);
 			break;
@@ -1883,19 +2411,28 @@ tsetattr(int *attr, int l)
 			term.c.attr.mode &= ~ATTR_STRUCK;
 			break;
 		case 38:
-			if ((idx = tdefcolor(attr, &i, l)) >= 0)
+			if ((idx = tdefcolor(attr, sub, &i, l)) >= 0)
 				term.c.attr.fg = idx;
 			break;
 		case 39:
 			term.c.attr.fg = defaultfg;
 			break;
 		case 48:
-			if ((idx = tdefcolor(attr, &i, l)) >= 0)
+			if ((idx = tdefcolor(attr, sub, &i, l)) >= 0)
 				term.c.attr.bg = idx;
 			break;
 		case 49:
 			term.c.attr.bg = defaultbg;
 			break;
+		case 58:
+			if ((idx = tdefcolor(attr, sub, &i, l)) >= 0) {
+				term.c.attr.ulcolor = idx;
+				term.c.attr.ul |= UL_COLOR;
+			}
+			break;
+		case 59:
+			term.c.attr.ul &= ~UL_COLOR;
+			break;
 		default:
 			if (BETWEEN(attr[i], 30, 37)) {
 				term.c.attr.fg = attr[i] - 30;
@@ -1912,6 +2449,9 @@ tsetattr(int *attr, int l)
 			}
 			break;
 		}
+		/* skip subparameters the attribute didn't use */
+		while (i + 1 < l && sub[i + 1])
+			i++;
 	}
 }
 
@@ -1932,82 +2472,535 @@ tsetscroll(int t, int b)
 }
 
 void
-tsetmode(int priv, int set, int *args, int narg)
+tsetmargins(int l, int r)
 {
-	int *lim, mode;
-	int alt;
+	LIMIT(l, 0, term.col-1);
+	LIMIT(r, l, term.col-1);
+	term.left = l;
+	term.right = r;
+}
 
-	for (lim = args + narg; args < lim; ++args) {
-		if (priv) {
-			switch (*args) {
-			case 1: /* DECCKM -- Cursor key */
-				MODBIT(term.mode, set, MODE_APPCURSOR);
-				break;
-			case 5: /* DECSCNM -- Reverse video */
-				mode = term.mode;
-				MODBIT(term.mode, set, MODE_REVERSE);
-				if (mode != term.mode)
-					redraw();
-				break;
-			case 6: /* DECOM -- Origin */
-				MODBIT(term.c.state, set, CURSOR_ORIGIN);
-				tmoveato(0, 0);
-				break;
-			case 7: /* DECAWM -- Auto wrap */
-				MODBIT(term.mode, set, MODE_WRAP);
-				break;
-			case 0:  /* Error (IGNORED) */
-			case 2:  /* DECANM -- ANSI/VT52 (IGNORED) */
-			case 3:  /* DECCOLM -- Column  (IGNORED) */
-			case 4:  /* DECSCLM -- Scroll (IGNORED) */
-			case 8:  /* DECARM -- Auto repeat (IGNORED) */
-			case 18: /* DECPFF -- Printer feed (IGNORED) */
-			case 19: /* DECPEX -- Printer extent (IGNORED) */
-			case 42: /* DECNRCM -- National characters (IGNORED) */
-			case 12: /* att610 -- Start blinking cursor (IGNORED) */
-				break;
-			case 25: /* DECTCEM -- Text Cursor Enable Mode */
-				MODBIT(term.mode, !set, MODE_HIDE);
-				break;
-			case 9:    /* X10 mouse compatibility mode */
-				xsetpointermotion(0);
-				MODBIT(term.mode, 0, MODE_MOUSE);
-				MODBIT(term.mode, set, MODE_MOUSEX10);
-				break;
-			case 1000: /* 1000: report button press */
-				xsetpointermotion(0);
-				MODBIT(term.mode, 0, MODE_MOUSE);
-				MODBIT(term.mode, set, MODE_MOUSEBTN);
-				break;
-			case 1002: /* 1002: report motion on button press */
-				xsetpointermotion(0);
-				MODBIT(term.mode, 0, MODE_MOUSE);
-				MODBIT(term.mode, set, MODE_MOUSEMOTION);
-				break;
-			case 1003: /* 1003: enable all mouse motions */
-				xsetpointermotion(set);
-				MODBIT(term.mode, 0, MODE_MOUSE);
-				MODBIT(term.mode, set, MODE_MOUSEMANY);
-				break;
-			case 1004: /* 1004: send focus events to tty */
-				MODBIT(term.mode, set, MODE_FOCUS);
-				break;
-			case 1006: /* 1006: extended reporting mode */
-				MODBIT(term.mode, set, MODE_MOUSESGR);
-				break;
-			case 1034:
-				MODBIT(term.mode, set, MODE_8BIT);
-				break;
-			case 1049: /* swap screen & set/restore cursor as xterm */
-				if (!allowaltscreen)
-					break;
+void
+tdeccolm(int set)
+{
+	int col = set ? 132 : 80;
 
-				set ? tsavecursor() : tloadcursor();
-				/* FALLTHROUGH */
-			case 47: /* swap screen */
-			case 1047:
-				if (!allowaltscreen)
-					break;
+	if (!IS_SET(MODE_ALLOWCOLM))
+		return;
+	MODBIT(term.mode, set, MODE_132COLS);
+
+	if (col != term.col) {
+		tresize(col, term.row);
+		if (!xw.isfixed) {
+			xw.w = 2 * borderpx + col * xw.cw;
+			XResizeWindow(xw.dpy, xw.win, xw.w, xw.h);
+			xresize(col, term.row);
+			xhints();
+		}
+		ttyresize();
+	}
+	/* DECCOLM always clears the screen and resets the margins */
+	tsetscroll(0, term.row-1);
+	tsetmargins(0, term.col-1);
+	tclearregion(0, 0, term.col-1, term.row-1);
+	tmoveato(0, 0);
+}
+
+/*
+ * Converts the Pt;Pl;Pb;Pr of a rectangular operation to absolute
+ * coordinates, relative to the margins when decom is set.
+ */
+int
+trect(int *arg, int *x1, int *y1, int *x2, int *y2)
+{
+	int top = 0, left = 0, bot = term.row-1, right = term.col-1;
+
+	if (term.c.state & CURSOR_ORIGIN) {
+		top = term.top;
+		left = term.left;
+		bot = term.bot;
+		right = term.right;
+	}
+	DEFAULT(arg[0], 1);
+	DEFAULT(arg[1], 1);
+	DEFAULT(arg[2], bot - top + 1);
+	DEFAULT(arg[3], right - left + 1);
+
+	*y1 = top + arg[0] - 1;
+	*x1 = left + arg[1] - 1;
+	*y2 = top + arg[2] - 1;
+	*x2 = left + arg[3] - 1;
+	LIMIT(*y1, top, bot);
+	LIMIT(*x1, left, right);
+	LIMIT(*y2, top, bot);
+	LIMIT(*x2, left, right);
+
+	return *y1 <= *y2 && *x1 <= *x2;
+}
+
+/* breaks up the wide characters crossing the edges of x1..x2 */
+void
+tsplitwide(int x1, int x2, int y)
+{
+	Glyph *line = term.line[y];
+
+	if (line[x1].mode & ATTR_WDUMMY) {
+		line[x1-1].u = ' ';
+		line[x1-1].mode &= ~ATTR_WIDE;
+	}
+	if ((line[x2].mode & ATTR_WIDE) && x2+1 < term.col) {
+		line[x2+1].u = ' ';
+		line[x2+1].mode &= ~ATTR_WDUMMY;
+	}
+}
+
+void
+tfillrect(Rune u, int *arg)
+{
+	int x, y, x1, y1, x2, y2;
+
+	if (!trect(arg, &x1, &y1, &x2, &y2))
+		return;
+
+	for (y = y1; y <= y2; y++) {
+		for (x = x1; x <= x2; x++)
+			tsetchar(u, &term.c.attr, x, y);
+	}
+}
+
+void
+terasrect(int *arg)
+{
+	int y, x1, y1, x2, y2;
+
+	if (!trect(arg, &x1, &y1, &x2, &y2))
+		return;
+
+	for (y = y1; y <= y2; y++)
+		tsplitwide(x1, x2, y);
+	tclearregion(x1, y1, x2, y2);
+}
+
+void
+tcopyrect(int *arg)
+{
+	int x, y, x1, y1, x2, y2, dx, dy, w, h;
+	int dst[4];
+	Glyph *buf, *gp;
+
+	if (!trect(arg, &x1, &y1, &x2, &y2))
+		return;
+
+	/* the destination is given as Ptd;Pld, pages are ignored */
+	dst[0] = arg[5];
+	dst[1] = arg[6];
+	dst[2] = dst[3] = 0;
+	trect(dst, &dx, &dy, &x, &y);
+	w = MIN(x2 - x1, x - dx) + 1;
+	h = MIN(y2 - y1, y - dy) + 1;
+
+	buf = xmalloc(w * h * sizeof(Glyph));
+	for (y = 0; y < h; y++)
+		memcpy(&buf[y * w], &term.line[y1 + y][x1], w * sizeof(Glyph));
+
+	for (y = 0; y < h; y++) {
+		tsplitwide(dx, dx + w - 1, dy + y);
+		gp = &term.line[dy + y][dx];
+		memcpy(gp, &buf[y * w], w * sizeof(Glyph));
+
+		/* halves of wide characters cut off by the source area */
+		if (gp[0].mode & ATTR_WDUMMY) {
+			gp[0].u = ' ';
+			gp[0].mode &= ~ATTR_WDUMMY;
+		}
+		if (gp[w-1].mode & ATTR_WIDE) {
+			gp[w-1].u = ' ';
+			gp[w-1].mode &= ~ATTR_WIDE;
+		}
+		for (x = 0; x < w; x++) {
+			if (selected(dx + x, dy + y))
+				selclear(NULL);
+		}
+	}
+	free(buf);
+	tsetdirt(dy, dy + h - 1);
+}
+
+/* DECCARA changes, DECRARA reverses the attributes in the area */
+void
+tattrrect(int *arg, int narg, int rev)
+{
+	int i, x, y, x1, y1, x2, y2, xs, xe;
+	ushort set = 0, clr = 0, flip = 0, attr;
+	Glyph *gp;
+
+	if (!trect(arg, &x1, &y1, &x2, &y2))
+		return;
+
+	for (i = 4; i < MAX(narg, 5); i++) {
+		switch (arg[i]) {
+		case 0:
+			attr = ATTR_BOLD|ATTR_UNDERLINE|ATTR_BLINK|ATTR_REVERSE;
+			if (rev) {
+				flip |= attr;
+			} else {
+				clr |= attr;
+				set &= ~attr;
+			}
+			continue;
+		case 1:
+		case 22:
+			attr = ATTR_BOLD;
+			break;
+		case 4:
+		case 24:
+			attr = ATTR_UNDERLINE;
+			break;
+		case 5:
+		case 25:
+			attr = ATTR_BLINK;
+			break;
+		case 7:
+		case 27:
+			attr = ATTR_REVERSE;
+			break;
+		case 8:
+		case 28:
+			attr = ATTR_INVISIBLE;
+			break;
+		default:
+			continue;
+		}
+		if (rev) {
+			if (arg[i] < 10)
+				flip ^= attr;
+		} else if (arg[i] < 10) {
+			set |= attr;
+			clr &= ~attr;
+		} else {
+			clr |= attr;
+			set &= ~attr;
+		}
+	}
+
+	for (y = y1; y <= y2; y++) {
+		xs = x1;
+		xe = x2;
+		/* the stream extent wraps from line to line */
+		if (!IS_SET(MODE_RECTEXT)) {
+			if (y != y1)
+				xs = 0;
+			if (y != y2)
+				xe = term.col-1;
+		}
+		for (x = xs; x <= xe; x++) {
+			gp = &term.line[y][x];
+			gp->mode = ((gp->mode & ~clr) | set) ^ flip;
+		}
+	}
+	tsetdirt(y1, y2);
+}
+
+void
+tsettitle(char *p)
+{
+	free(title);
+	if (p) {
+		title = xstrdup(p);
+		xsettitle(title);
+	} else {
+		title = NULL;
+		xresettitle();
+	}
+}
+
+int
+twinopallowed(int op)
+{
+	int *p;
+
+	for (p = allowwindowops; *p; p++) {
+		if (*p == op)
+			return 1;
+	}
+	return 0;
+}
+
+/* XTWINOPS -- CSI Ps ; Ps ; Ps t */
+void
+twinops(int *arg)
+{
+	char buf[64], *name;
+	int len = 0, x, y, w, h;
+	Window child;
+
+	if (!twinopallowed(arg[0])) {
+		fprintf(stderr, "erresc: window operation %d not allowed\n",
+				arg[0]);
+		return;
+	}
+
+	switch (arg[0]) {
+	case 1: /* de-iconify */
+		XMapWindow(xw.dpy, xw.win);
+		break;
+	case 2: /* iconify */
+		XIconifyWindow(xw.dpy, xw.win, xw.scr);
+		break;
+	case 3: /* move to x ; y */
+		XMoveWindow(xw.dpy, xw.win, arg[1], arg[2]);
+		break;
+	case 4: /* resize to height ; width in pixels */
+	case 8: /* resize to height ; width in characters */
+		if (xw.isfixed)
+			break;
+		h = arg[1] ? arg[1] : (arg[0] == 4 ? xw.th : term.row);
+		w = arg[2] ? arg[2] : (arg[0] == 4 ? xw.tw : term.col);
+		if (arg[0] == 8) {
+			h *= xw.ch;
+			w *= xw.cw;
+		}
+		h += 2 * borderpx;
+		w += 2 * borderpx;
+		XResizeWindow(xw.dpy, xw.win, w, h);
+		cresize(w, h);
+		ttyresize();
+		break;
+	case 5: /* raise */
+		XRaiseWindow(xw.dpy, xw.win);
+		break;
+	case 6: /* lower */
+		XLowerWindow(xw.dpy, xw.win);
+		break;
+	case 7: /* refresh */
+		redraw();
+		break;
+	case 11: /* report window state */
+		len = snprintf(buf, sizeof(buf), "\033[%dt",
+				(xw.state & WIN_VISIBLE) ? 1 : 2);
+		break;
+	case 13: /* report window position */
+		XTranslateCoordinates(xw.dpy, xw.win,
+				XRootWindow(xw.dpy, xw.scr), 0, 0,
+				&x, &y, &child);
+		len = snprintf(buf, sizeof(buf), "\033[3;%d;%dt", x, y);
+		break;
+	case 14: /* report text area, or window with 2, size in pixels */
+		if (arg[1] == 2) {
+			len = snprintf(buf, sizeof(buf), "\033[4;%d;%dt",
+					xw.h, xw.w);
+		} else {
+			len = snprintf(buf, sizeof(buf), "\033[4;%d;%dt",
+					xw.th, xw.tw);
+		}
+		break;
+	case 15: /* report screen size in pixels */
+		len = snprintf(buf, sizeof(buf), "\033[5;%d;%dt",
+				DisplayHeight(xw.dpy, xw.scr),
+				DisplayWidth(xw.dpy, xw.scr));
+		break;
+	case 16: /* report character cell size in pixels */
+		len = snprintf(buf, sizeof(buf), "\033[6;%d;%dt",
+				xw.ch, xw.cw);
+		break;
+	case 18: /* report text area size in characters */
+		len = snprintf(buf, sizeof(buf), "\033[8;%d;%dt",
+				term.row, term.col);
+		break;
+	case 19: /* report screen size in characters */
+		len = snprintf(buf, sizeof(buf), "\033[9;%d;%dt",
+				DisplayHeight(xw.dpy, xw.scr) / xw.ch,
+				DisplayWidth(xw.dpy, xw.scr) / xw.cw);
+		break;
+	case 20: /* report icon label */
+	case 21: /* report window title */
+		name = title;
+		if (!name && !XFetchName(xw.dpy, xw.win, &name))
+			name = NULL;
+		ttywrite(arg[0] == 20 ? "\033]L" : "\033]l", 3);
+		if (name)
+			ttywrite(name, strlen(name));
+		ttywrite("\033\\", 2);
+		if (name && name != title)
+			XFree(name);
+		break;
+	case 22: /* push title, 0 and 2 are the window title */
+		if (arg[1] == 1)
+			break;
+		if (titletop == TITLE_STACK_SIZ) {
+			/* drop the oldest */
+			free(titlestack[0]);
+			memmove(titlestack, titlestack + 1,
+					(TITLE_STACK_SIZ - 1) * sizeof(char *));
+			titletop--;
+		}
+		titlestack[titletop++] = title ? xstrdup(title) : NULL;
+		break;
+	case 23: /* pop title */
+		if (arg[1] == 1 || titletop == 0)
+			break;
+		tsettitle(titlestack[--titletop]);
+		free(titlestack[titletop]);
+		break;
+	default:
+		fprintf(stderr, "erresc: unknown window operation %d\n",
+				arg[0]);
+		break;
+	}
+	if (len > 0)
+		ttywrite(buf, len);
+}
+
+/*
+ * Kitty keyboard protocol: CSI > flags u pushes, CSI < n u pops, CSI = flags
+ * ; mode u changes and CSI ? u queries the flags of the current screen.
+ */
+void
+tkbdflags(char op, int flags, int mode)
+{
+	int alt = IS_SET(MODE_ALTSCREEN);
+	uint *stack = term.kbdflags[alt];
+	int *top = &term.kbdtop[alt];
+	char buf[16];
+	int len;
+
+	switch (op) {
+	case '>':
+		if (*top == KBD_STACK_SIZ - 1) {
+			memmove(stack, stack + 1,
+			        (KBD_STACK_SIZ - 1) * sizeof(*stack));
+		} else {
+			(*top)++;
+		}
+		stack[*top] = flags & KBD_SUPPORTED;
+		break;
+	case '<':
+		DEFAULT(flags, 1);
+		/* emptying the stack resets the flags */
+		if ((*top -= flags) <= 0) {
+			*top = 0;
+			stack[0] = 0;
+		}
+		break;
+	case '=':
+		switch (mode) {
+		case 0:
+		case 1:
+			stack[*top] = flags & KBD_SUPPORTED;
+			break;
+		case 2:
+			stack[*top] |= flags & KBD_SUPPORTED;
+			break;
+		case 3:
+			stack[*top] &= ~flags;
+			break;
+		}
+		break;
+	case '?':
+		len = snprintf(buf, sizeof(buf), "\033[?%uu", stack[*top]);
+		ttywrite(buf, len);
+		break;
+	}
+}
+
+void
+tsetmode(int priv, int set, int *args, int narg)
+{
+	int *lim, mode;
+	int alt;
+
+	for (lim = args + narg; args < lim; ++args) {
+		if (priv) {
+			switch (*args) {
+			case 1: /* DECCKM -- Cursor key */
+				MODBIT(term.mode, set, MODE_APPCURSOR);
+				break;
+			case 5: /* DECSCNM -- Reverse video */
+				mode = term.mode;
+				MODBIT(term.mode, set, MODE_REVERSE);
+				if (mode != term.mode)
+					redraw();
+				break;
+			case 6: /* DECOM -- Origin */
+				MODBIT(term.c.state, set, CURSOR_ORIGIN);
+				tmoveato(0, 0);
+				break;
+			case 7: /* DECAWM -- Auto wrap */
+				MODBIT(term.mode, set, MODE_WRAP);
+				break;
+			case 0:  /* Error (IGNORED) */
+			case 4:  /* DECSCLM -- Scroll (IGNORED) */
+			case 8:  /* DECARM -- Auto repeat (IGNORED) */
+			case 18: /* DECPFF -- Printer feed (IGNORED) */
+			case 19: /* DECPEX -- Printer extent (IGNORED) */
+				break;
+			case 42: /* DECNRCM -- National replacement characters */
+				term.nrcs = set;
+				break;
+			case 12: /* att610 -- Start blinking cursor */
+				/* switch between the DECSCUSR blink/steady pairs */
+				if (set && (xw.cursor == 2 || xw.cursor == 4 ||
+						xw.cursor == 6)) {
+					xw.cursor--;
+				} else if (!set && (xw.cursor == 0 ||
+						xw.cursor == 1 || xw.cursor == 3 ||
+						xw.cursor == 5)) {
+					xw.cursor = xw.cursor ? xw.cursor + 1 : 2;
+				}
+				break;
+			case 25: /* DECTCEM -- Text Cursor Enable Mode */
+				MODBIT(term.mode, !set, MODE_HIDE);
+				break;
+			case 9:    /* X10 mouse compatibility mode */
+				MODBIT(term.mode, 0, MODE_MOUSE);
+				MODBIT(term.mode, set, MODE_MOUSEX10);
+				break;
+			case 1000: /* 1000: report button press */
+				MODBIT(term.mode, 0, MODE_MOUSE);
+				MODBIT(term.mode, set, MODE_MOUSEBTN);
+				break;
+			case 1002: /* 1002: report motion on button press */
+				MODBIT(term.mode, 0, MODE_MOUSE);
+				MODBIT(term.mode, set, MODE_MOUSEMOTION);
+				break;
+			case 1003: /* 1003: enable all mouse motions */
+				MODBIT(term.mode, 0, MODE_MOUSE);
+				MODBIT(term.mode, set, MODE_MOUSEMANY);
+				break;
+			case 1004: /* 1004: send focus events to tty */
+				MODBIT(term.mode, set, MODE_FOCUS);
+				break;
+			case 1006: /* 1006: extended reporting mode */
+				MODBIT(term.mode, set, MODE_MOUSESGR);
+				break;
+			case 1016: /* 1016: SGR reporting in pixels */
+				MODBIT(term.mode, set, MODE_MOUSEPIXELS);
+				break;
+			case 1015: /* urxvt mangled mouse mode; incompatible
+				      and can be mistaken for other control
+				      codes. */
+				if (allowlegacymouse)
+					MODBIT(term.mode, set, MODE_MOUSEURXVT);
+				break;
+			case 1005: /* UTF-8 mouse mode; will confuse
+				      applications not supporting UTF-8
+				      and luit. */
+				if (allowlegacymouse)
+					MODBIT(term.mode, set, MODE_MOUSEUTF8);
+				break;
+			case 1034:
+				MODBIT(term.mode, set, MODE_8BIT);
+				break;
+			case 1049: /* swap screen & set/restore cursor as xterm */
+				if (!allowaltscreen)
+					break;
+
+				set ? tsavecursor() : tloadcursor();
+				/* FALLTHROUGH */
+			case 47: /* swap screen */
+			case 1047:
+				if (!allowaltscreen)
+					break;
 				alt = IS_SET(MODE_ALTSCREEN);
 				if (alt) {
 					tclearregion(0, 0, term.col-1,
@@ -2024,15 +3017,29 @@ tsetmode(int priv, int set, int *args, int narg)
 			case 2004: /* 2004: bracketed paste mode */
 				MODBIT(term.mode, set, MODE_BRCKTPASTE);
 				break;
+			case 2026: /* synchronized update */
+				if (set && !IS_SET(MODE_SYNC))
+					clock_gettime(CLOCK_MONOTONIC, &syncstart);
+				else if (!set && IS_SET(MODE_SYNC))
+					syncended++;
+				MODBIT(term.mode, set, MODE_SYNC);
+				break;
+			case 3: /* DECCOLM -- 80/132 column mode */
+				tdeccolm(set);
+				break;
+			case 2: /* DECANM -- ANSI/VT52, reset enters VT52 */
+				MODBIT(term.mode, !set, MODE_VT52);
+				break;
+			case 40: /* allow 80 -> 132 column mode */
+				MODBIT(term.mode, set, MODE_ALLOWCOLM);
+				break;
+			case 69: /* DECLRMM -- Left/right margin mode */
+				MODBIT(term.mode, set, MODE_LRMM);
+				tsetmargins(0, term.col-1);
+				break;
 			/* Not implemented mouse modes. See comments there. */
 			case 1001: /* mouse highlight mode; can hang the
 				      terminal by design when implemented. */
-			case 1005: /* UTF-8 mouse mode; will confuse
-				      applications not supporting UTF-8
-				      and luit. */
-			case 1015: /* urxvt mangled mouse mode; incompatible
-				      and can be mistaken for other control
-				      codes. */
 			default:
 				fprintf(stderr,
 					"erresc: unknown private set/reset mode %d\n",
@@ -2065,11 +3072,139 @@ tsetmode(int priv, int set, int *args, int narg)
 	}
 }
 
-void
-csihandle(void)
-{
-	char buf[40];
+/*
+ * Mode state as reported by DECRQM: 0 not recognized, 1 set, 2 reset,
+ * 3 permanently set, 4 permanently reset. Keep in sync with tsetmode.
+ */
+int
+tgetmode(int priv, int mode)
+{
+	int set;
+
+	if (priv) {
+		switch (mode) {
+		case 1: /* DECCKM */
+			set = IS_SET(MODE_APPCURSOR);
+			break;
+		case 5: /* DECSCNM */
+			set = IS_SET(MODE_REVERSE);
+			break;
+		case 6: /* DECOM */
+			set = term.c.state & CURSOR_ORIGIN;
+			break;
+		case 7: /* DECAWM */
+			set = IS_SET(MODE_WRAP);
+			break;
+		case 25: /* DECTCEM */
+			set = !IS_SET(MODE_HIDE);
+			break;
+		case 9:
+			set = IS_SET(MODE_MOUSEX10);
+			break;
+		case 1000:
+			set = IS_SET(MODE_MOUSEBTN);
+			break;
+		case 1002:
+			set = IS_SET(MODE_MOUSEMOTION);
+			break;
+		case 1003:
+			set = IS_SET(MODE_MOUSEMANY);
+			break;
+		case 1004:
+			set = IS_SET(MODE_FOCUS);
+			break;
+		case 1006:
+			set = IS_SET(MODE_MOUSESGR);
+			break;
+		case 1016:
+			set = IS_SET(MODE_MOUSEPIXELS);
+			break;
+		case 1015:
+			if (!allowlegacymouse)
+				return 4;
+			set = IS_SET(MODE_MOUSEURXVT);
+			break;
+		case 1005:
+			if (!allowlegacymouse)
+				return 4;
+			set = IS_SET(MODE_MOUSEUTF8);
+			break;
+		case 12: /* att610 */
+			set = xw.cursor == 0 || xw.cursor == 1 ||
+			      xw.cursor == 3 || xw.cursor == 5;
+			break;
+		case 1034:
+			set = IS_SET(MODE_8BIT);
+			break;
+		case 47:
+		case 1047:
+		case 1049:
+			if (!allowaltscreen)
+				return 4;
+			set = IS_SET(MODE_ALTSCREEN);
+			break;
+		case 1048: /* only saves/restores the cursor */
+			set = 0;
+			break;
+		case 2004:
+			set = IS_SET(MODE_BRCKTPASTE);
+			break;
+		case 2026:
+			set = IS_SET(MODE_SYNC);
+			break;
+		case 69:
+			set = IS_SET(MODE_LRMM);
+			break;
+		case 3:
+			set = IS_SET(MODE_132COLS);
+			break;
+		case 2:
+			set = !IS_SET(MODE_VT52);
+			break;
+		case 42:
+			set = term.nrcs;
+			break;
+		case 40:
+			set = IS_SET(MODE_ALLOWCOLM);
+			break;
+		case 0:
+		case 4:
+		case 8:
+		case 18:
+		case 19:
+		case 1001:
+			return 4;
+		default:
+			return 0;
+		}
+	} else {
+		switch (mode) {
+		case 2: /* KAM */
+			set = IS_SET(MODE_KBDLOCK);
+			break;
+		case 4: /* IRM */
+			set = IS_SET(MODE_INSERT);
+			break;
+		case 12: /* SRM */
+			set = !IS_SET(MODE_ECHO);
+			break;
+		case 20: /* LNM */
+			set = IS_SET(MODE_CRLF);
+			break;
+		default:
+			return 0;
+		}
+	}
+
+	return set ? 1 : 2;
+}
+
+void
+csihandle(void)
+{
+	char buf[40];
 	int len;
+	void (*clear)(int, int, int, int);
 
 	switch (csiescseq.mode[0]) {
 	default:
@@ -2082,6 +3217,18 @@ csihandle(void)
 		DEFAULT(csiescseq.arg[0], 1);
 		tinsertblank(csiescseq.arg[0]);
 		break;
+	case 't': /* XTWINOPS -- Window manipulation */
+		if (csiescseq.priv)
+			goto unknown;
+		twinops(csiescseq.arg);
+		break;
+	case 'b': /* REP -- if last char is printable print it <n> more times */
+		LIMIT(csiescseq.arg[0], 1, 65535);
+		if (term.lastc) {
+			while (csiescseq.arg[0]-- > 0)
+				tputc(term.lastc);
+		}
+		break;
 	case 'A': /* CUU -- Cursor <n> Up */
 		DEFAULT(csiescseq.arg[0], 1);
 		tmoveto(term.c.x, term.c.y-csiescseq.arg[0]);
@@ -2111,8 +3258,31 @@ csihandle(void)
 		}
 		break;
 	case 'c': /* DA -- Device Attributes */
-		if (csiescseq.arg[0] == 0)
+		if (csiescseq.arg[0] != 0)
+			break;
+		switch (csiescseq.priv) {
+		case 0: /* primary DA */
 			ttywrite(vtiden, sizeof(vtiden) - 1);
+			break;
+		case '>': /* secondary DA -- VT220, version, ROM */
+			len = snprintf(buf, sizeof(buf), "\033[>1;%d;0c",
+					VERSION_MAJOR * 10000 +
+					VERSION_MINOR * 100 + VERSION_PATCH);
+			ttywrite(buf, len);
+			break;
+		case '=': /* tertiary DA -- unit id */
+			ttywrite("\033P!|00000000\033\\", 14);
+			break;
+		default:
+			goto unknown;
+		}
+		break;
+	case 'q': /* XTVERSION -- Report name and version */
+		if (csiescseq.priv != '>' || csiescseq.arg[0] != 0)
+			goto unknown;
+		len = snprintf(buf, sizeof(buf), "\033P>|stru(%s)\033\\",
+				VERSION);
+		ttywrite(buf, len);
 		break;
 	case 'C': /* CUF -- Cursor <n> Forward */
 	case 'a': /* HPR -- Cursor <n> Forward */
@@ -2150,6 +3320,11 @@ csihandle(void)
 		break;
 	case 'H': /* CUP -- Move to <row> <col> */
 	case 'f': /* HVP */
+		if (csiescseq.priv == '>') { /* XTFMTKEYS */
+			if (csiescseq.arg[0] == 4)
+				term.fmtkeys = csiescseq.arg[1] == 1;
+			break;
+		}
 		DEFAULT(csiescseq.arg[0], 1);
 		DEFAULT(csiescseq.arg[1], 1);
 		tmoveato(csiescseq.arg[1]-1, csiescseq.arg[0]-1);
@@ -2159,42 +3334,47 @@ csihandle(void)
 		tputtab(csiescseq.arg[0]);
 		break;
 	case 'J': /* ED -- Clear screen */
+		/* DECSED -- Selective erase in display */
+		clear = csiescseq.priv == '?' ? tselectiveclear : tclearregion;
 		selclear(NULL);
 		switch (csiescseq.arg[0]) {
 		case 0: /* below */
-			tclearregion(term.c.x, term.c.y, term.col-1, term.c.y);
-			if (term.c.y < term.row-1) {
-				tclearregion(0, term.c.y+1, term.col-1,
-						term.row-1);
-			}
+			clear(term.c.x, term.c.y, term.col-1, term.c.y);
+			if (term.c.y < term.row-1)
+				clear(0, term.c.y+1, term.col-1, term.row-1);
 			break;
 		case 1: /* above */
 			if (term.c.y > 1)
-				tclearregion(0, 0, term.col-1, term.c.y-1);
-			tclearregion(0, term.c.y, term.c.x, term.c.y);
+				clear(0, 0, term.col-1, term.c.y-1);
+			clear(0, term.c.y, term.c.x, term.c.y);
 			break;
 		case 2: /* all */
-			tclearregion(0, 0, term.col-1, term.row-1);
+			clear(0, 0, term.col-1, term.row-1);
 			break;
 		default:
 			goto unknown;
 		}
 		break;
 	case 'K': /* EL -- Clear line */
+		/* DECSEL -- Selective erase in line */
+		clear = csiescseq.priv == '?' ? tselectiveclear : terase;
 		switch (csiescseq.arg[0]) {
 		case 0: /* right */
-			tclearregion(term.c.x, term.c.y, term.col-1,
-					term.c.y);
+			clear(term.c.x, term.c.y, term.col-1, term.c.y);
 			break;
 		case 1: /* left */
-			tclearregion(0, term.c.y, term.c.x, term.c.y);
+			clear(0, term.c.y, term.c.x, term.c.y);
 			break;
 		case 2: /* all */
-			tclearregion(0, term.c.y, term.col-1, term.c.y);
+			clear(0, term.c.y, term.col-1, term.c.y);
 			break;
 		}
 		break;
 	case 'S': /* SU -- Scroll <n> line up */
+		if (csiescseq.priv == '?') { /* XTSMGRAPHICS */
+			xtsmgraphics(csiescseq.arg[0], csiescseq.arg[1]);
+			break;
+		}
 		DEFAULT(csiescseq.arg[0], 1);
 		tscrollup(term.top, csiescseq.arg[0], 0);
 		break;
@@ -2207,6 +3387,8 @@ csihandle(void)
 		tinsertblankline(csiescseq.arg[0]);
 		break;
 	case 'l': /* RM -- Reset Mode */
+		if (csiescseq.priv && csiescseq.priv != '?')
+			goto unknown;
 		tsetmode(csiescseq.priv, 0, csiescseq.arg, csiescseq.narg);
 		break;
 	case 'M': /* DL -- Delete <n> lines */
@@ -2231,12 +3413,32 @@ csihandle(void)
 		tmoveato(term.c.x, csiescseq.arg[0]-1);
 		break;
 	case 'h': /* SM -- Set terminal mode */
+		if (csiescseq.priv && csiescseq.priv != '?')
+			goto unknown;
 		tsetmode(csiescseq.priv, 1, csiescseq.arg, csiescseq.narg);
 		break;
 	case 'm': /* SGR -- Terminal attribute (color) */
-		tsetattr(csiescseq.arg, csiescseq.narg);
+		if (csiescseq.priv == '>') { /* XTMODKEYS */
+			if (csiescseq.arg[0] == 4)
+				term.modkeys = MIN(csiescseq.arg[1], 2);
+			break;
+		}
+		if (csiescseq.priv == '?') { /* XTQMODKEYS */
+			if (csiescseq.arg[0] == 4) {
+				len = snprintf(buf, sizeof(buf), "\033[>4;%dm",
+				               term.modkeys);
+				ttywrite(buf, len);
+			}
+			break;
+		}
+		tsetattr(csiescseq.arg, csiescseq.sub, csiescseq.narg);
 		break;
 	case 'n': /* DSR – Device Status Report (cursor position) */
+		if (csiescseq.priv == '>') { /* disable modify keys */
+			if (csiescseq.arg[0] == 4)
+				term.modkeys = 0;
+			break;
+		}
 		if (csiescseq.arg[0] == 6) {
 			len = snprintf(buf, sizeof(buf),"\033[%i;%iR",
 					term.c.y+1, term.c.x+1);
@@ -2253,12 +3455,79 @@ csihandle(void)
 			tmoveato(0, 0);
 		}
 		break;
-	case 's': /* DECSC -- Save cursor position (ANSI.SYS) */
+	case 's': /* DECSLRM -- Set left and right margins */
+		if (IS_SET(MODE_LRMM)) {
+			DEFAULT(csiescseq.arg[0], 1);
+			DEFAULT(csiescseq.arg[1], term.col);
+			/* margins that leave less than two columns are ignored */
+			if (csiescseq.arg[0] >= MIN(csiescseq.arg[1], term.col))
+				break;
+			tsetmargins(csiescseq.arg[0]-1, csiescseq.arg[1]-1);
+			tmoveato(0, 0);
+			break;
+		}
+		/* DECSC -- Save cursor position (ANSI.SYS) */
 		tsavecursor();
 		break;
 	case 'u': /* DECRC -- Restore cursor position (ANSI.SYS) */
+		if (csiescseq.priv) {
+			tkbdflags(csiescseq.priv, csiescseq.arg[0],
+			          csiescseq.arg[1]);
+			break;
+		}
 		tloadcursor();
 		break;
+	case '$':
+		switch (csiescseq.mode[1]) {
+		case 'p': /* DECRQM -- Request mode */
+			len = snprintf(buf, sizeof(buf), "\033[%s%d;%d$y",
+					csiescseq.priv == '?' ? "?" : "",
+					csiescseq.arg[0],
+					tgetmode(csiescseq.priv == '?',
+						csiescseq.arg[0]));
+			ttywrite(buf, len);
+			break;
+		case 'x': /* DECFRA -- Fill rectangular area */
+			if (!BETWEEN(csiescseq.arg[0], 32, 126) &&
+			    !BETWEEN(csiescseq.arg[0], 160, 255))
+				break;
+			tfillrect(csiescseq.arg[0], &csiescseq.arg[1]);
+			break;
+		case 'z': /* DECERA -- Erase rectangular area */
+			terasrect(csiescseq.arg);
+			break;
+		case 'v': /* DECCRA -- Copy rectangular area */
+			tcopyrect(csiescseq.arg);
+			break;
+		case 'r': /* DECCARA -- Change attributes in rectangular area */
+			tattrrect(csiescseq.arg, csiescseq.narg, 0);
+			break;
+		case 't': /* DECRARA -- Reverse attributes in rectangular area */
+			tattrrect(csiescseq.arg, csiescseq.narg, 1);
+			break;
+		default:
+			goto unknown;
+		}
+		break;
+	case '"':
+		switch (csiescseq.mode[1]) {
+		case 'q': /* DECSCA -- Select character protection attribute */
+			MODBIT(term.c.attr.mode, csiescseq.arg[0] == 1,
+					ATTR_PROTECTED);
+			break;
+		default:
+			goto unknown;
+		}
+		break;
+	case '*':
+		switch (csiescseq.mode[1]) {
+		case 'x': /* DECSACE -- Select attribute change extent */
+			MODBIT(term.mode, csiescseq.arg[0] == 2, MODE_RECTEXT);
+			break;
+		default:
+			goto unknown;
+		}
+		break;
 	case ' ':
 		switch (csiescseq.mode[1]) {
 		case 'q': /* DECSCUSR -- Set Cursor Style */
@@ -2275,85 +3544,1424 @@ csihandle(void)
 	}
 }
 
-void
-csidump(void)
+void
+csidump(void)
+{
+	int i;
+	uint c;
+
+	printf("ESC[");
+	for (i = 0; i < csiescseq.len; i++) {
+		c = csiescseq.buf[i] & 0xff;
+		if (isprint(c)) {
+			putchar(c);
+		} else if (c == '\n') {
+			printf("(\\n)");
+		} else if (c == '\r') {
+			printf("(\\r)");
+		} else if (c == 0x1b) {
+			printf("(\\e)");
+		} else {
+			printf("(%02x)", c);
+		}
+	}
+	putchar('\n');
+}
+
+void
+csireset(void)
+{
+	memset(&csiescseq, 0, sizeof(csiescseq));
+}
+
+void
+strhandle(void)
+{
+	int j, narg, par;
+
+	term.esc &= ~(ESC_STR_END|ESC_STR);
+	if (strescseq.type == 'P') {
+		dcshandle();
+		return;
+	}
+	if (strescseq.type == '_') {
+		apchandle();
+		return;
+	}
+	strparse();
+	par = (narg = strescseq.narg) ? atoi(strescseq.args[0]) : 0;
+
+	switch (strescseq.type) {
+	case ']': /* OSC -- Operating System Command */
+		switch (par) {
+		case 0:
+		case 1:
+		case 2:
+			if (narg > 1)
+				tsettitle(strescseq.args[1]);
+			return;
+		case 7: /* current working directory */
+			if (narg > 1) {
+				/* the path may itself contain ';' */
+				for (j = 2; j < narg; j++)
+					strescseq.args[j][-1] = ';';
+				osc7handle(strescseq.args[1]);
+			}
+			return;
+		case 8: /* hyperlink */
+			if (narg > 2) {
+				/* the URI may itself contain ';' */
+				for (j = 3; j < narg; j++)
+					strescseq.args[j][-1] = ';';
+				osc8handle(strescseq.args[1], strescseq.args[2]);
+			}
+			return;
+		case 133: /* shell integration marks */
+			if (narg > 1)
+				osc133handle(strescseq.args[1][0]);
+			return;
+		case 52: /* manipulate selection data */
+			if (narg > 2)
+				osc52handle(strescseq.args[1], strescseq.args[2]);
+			return;
+		case 4: /* color set/query, in index;spec pairs */
+			if (narg < 3)
+				break;
+			for (j = 1; j + 1 < narg; j += 2)
+				oscsetcolor(atoi(strescseq.args[j]),
+						strescseq.args[j + 1]);
+			return;
+		case 104: /* color reset, all of them if no index is given */
+			if (narg == 1) {
+				xloadcols();
+				xcolorchanged(defaultbg);
+			}
+			for (j = 1; j < narg; j++)
+				oscsetcolor(atoi(strescseq.args[j]), NULL);
+			return;
+		case 10: /* set/query dynamic colors */
+		case 11:
+		case 12:
+		case 17:
+		case 19:
+			/* each further argument applies to the next color */
+			for (j = 1; j < narg && par <= 19; j++, par++)
+				oscdyncolor(par, strescseq.args[j]);
+			return;
+		case 110: /* reset dynamic colors */
+		case 111:
+		case 112:
+		case 117:
+		case 119:
+			oscdyncolor(par - 100, NULL);
+			return;
+		}
+		break;
+	case 'k': /* old title set compatibility */
+		tsettitle(strescseq.args[0]);
+		return;
+	case '^': /* PM -- Privacy Message */
+		return;
+	}
+
+	fprintf(stderr, "erresc: unknown str ");
+	strdump();
+}
+
+/*
+ * OSC 52 ; <selectors> ; <base64 data | ?>
+ * 'c' is the clipboard, 'p' and 's' are the primary selection.
+ * Cut buffers (0-7) are not supported.
+ */
+void
+osc52handle(char *sels, char *data)
+{
+	char *dec, *enc, *seltext, *buf;
+	int clip, prim, len;
+	Atom clipboard;
+
+	if (*sels == '\0')
+		sels = "s0";
+	clip = strchr(sels, 'c') != NULL;
+	prim = strpbrk(sels, "ps") != NULL;
+	if (!clip && !prim)
+		return;
+
+	if (!strcmp(data, "?")) {
+		if (!allowclipread)
+			return;
+		seltext = prim ? sel.primary : sel.clipboard;
+		enc = base64enc(seltext ? seltext : "");
+		len = strlen(enc) + 16;
+		buf = xmalloc(len);
+		len = snprintf(buf, len, "\033]52;%c;%s\033\\",
+				prim ? 'p' : 'c', enc);
+		ttywrite(buf, len);
+		free(enc);
+		free(buf);
+		return;
+	}
+
+	if (!allowclipwrite)
+		return;
+	if (!(dec = base64dec(data, NULL))) {
+		fprintf(stderr, "erresc: invalid base64 in OSC 52\n");
+		return;
+	}
+
+	if (clip) {
+		free(sel.clipboard);
+		sel.clipboard = xstrdup(dec);
+		clipboard = XInternAtom(xw.dpy, "CLIPBOARD", 0);
+		XSetSelectionOwner(xw.dpy, clipboard, xw.win, CurrentTime);
+	}
+	if (prim)
+		xsetsel(dec, CurrentTime);
+	else
+		free(dec);
+}
+
+/* a "?" spec queries the color, a NULL one resets it */
+void
+oscsetcolor(int idx, char *spec)
+{
+	char prefix[16];
+
+	if (!BETWEEN(idx, 0, LEN(dc.col) - 1)) {
+		fprintf(stderr, "erresc: invalid color index %d\n", idx);
+		return;
+	}
+
+	if (spec && !strcmp(spec, "?")) {
+		snprintf(prefix, sizeof(prefix), "4;%d", idx);
+		osccolorreply(prefix, idx);
+		return;
+	}
+
+	if (xsetcolorname(idx, spec))
+		fprintf(stderr, "erresc: invalid color %s\n", spec);
+	else
+		xcolorchanged(idx);
+}
+
+/*
+ * Dynamic colors are the default ones, so OSC 10 also changes the palette
+ * entry defaultfg points at. Selections are drawn in reverse video, so the
+ * highlight colors (17 and 19) are just the foreground and background and
+ * can't be set on their own. A NULL spec resets the color.
+ */
+void
+oscdyncolor(int par, char *spec)
+{
+	char prefix[8];
+	int idx;
+
+	switch (par) {
+	case 10: /* text foreground */
+		idx = defaultfg;
+		break;
+	case 11: /* text background */
+		idx = defaultbg;
+		break;
+	case 12: /* text cursor */
+		idx = defaultcs;
+		break;
+	case 17: /* highlight background */
+		idx = defaultfg;
+		break;
+	case 19: /* highlight foreground */
+		idx = defaultbg;
+		break;
+	default:
+		return;
+	}
+
+	if (spec && !strcmp(spec, "?")) {
+		snprintf(prefix, sizeof(prefix), "%d", par);
+		osccolorreply(prefix, idx);
+		return;
+	}
+
+	if (par == 17 || par == 19) {
+		if (spec)
+			fprintf(stderr, "erresc: OSC %d can't be set\n", par);
+		return;
+	}
+
+	if (xsetcolorname(idx, spec))
+		fprintf(stderr, "erresc: invalid color %s\n", spec);
+	else
+		xcolorchanged(idx);
+}
+
+/* prefix is what comes between OSC and the color, like "4;1" or "11" */
+void
+osccolorreply(const char *prefix, int idx)
+{
+	char buf[64];
+	int len;
+	XRenderColor *c = &dc.col[idx].color;
+
+	len = snprintf(buf, sizeof(buf), "\033]%s;rgb:%04x/%04x/%04x\033\\",
+			prefix, c->red, c->green, c->blue);
+	ttywrite(buf, len);
+}
+
+/*
+ * OSC 7 ; file://<host>/<path>
+ * Paths reported from other hosts, say over ssh, are ignored since they
+ * don't mean anything here.
+ */
+void
+osc7handle(char *uri)
+{
+	char *host, *path, *src, *dst;
+	char hostname[HOST_NAME_MAX + 1], hex[3] = {0};
+	size_t hostlen;
+
+	if (strncmp(uri, "file://", 7)) {
+		fprintf(stderr, "erresc: OSC 7 needs a file:// URI\n");
+		return;
+	}
+	host = uri + 7;
+	if (!(path = strchr(host, '/')))
+		return;
+
+	hostlen = path - host;
+	if (gethostname(hostname, sizeof(hostname)) < 0)
+		hostname[0] = '\0';
+	hostname[HOST_NAME_MAX] = '\0';
+	if (hostlen && (hostlen != strlen("localhost") ||
+			strncmp(host, "localhost", hostlen)) &&
+			(hostlen != strlen(hostname) ||
+			 strncmp(host, hostname, hostlen)))
+		return;
+
+	/* percent-decode in place */
+	for (src = dst = path; *src; src++, dst++) {
+		if (src[0] == '%' && isxdigit((uchar)src[1]) &&
+				isxdigit((uchar)src[2])) {
+			hex[0] = src[1];
+			hex[1] = src[2];
+			*dst = strtol(hex, NULL, 16);
+			src += 2;
+		} else {
+			*dst = *src;
+		}
+	}
+	*dst = '\0';
+
+	free(term.cwd);
+	term.cwd = xstrdup(path);
+}
+
+/*
+ * OSC 8 ; <params> ; <URI>
+ * params is a ':' separated list of key=value pairs, of which only id is
+ * used. An empty URI ends the current link.
+ */
+void
+osc8handle(char *params, char *uri)
+{
+	char *id = "", *p;
+
+	if (*uri == '\0') {
+		term.c.attr.link = 0;
+		return;
+	}
+
+	for (p = strtok(params, ":"); p; p = strtok(NULL, ":")) {
+		if (!strncmp(p, "id=", 3))
+			id = p + 3;
+	}
+	term.c.attr.link = linkintern(id, uri);
+}
+
+/*
+ * Returns the index + 1 of the link with the given id and uri, adding it
+ * to the table if it isn't there yet.
+ */
+ushort
+linkintern(char *id, char *uri)
+{
+	ushort i;
+	uint h = linkhash(id, uri);
+
+	for (i = linksbucket[h]; i; i = links[i - 1].next) {
+		if (!strcmp(links[i - 1].id, id) &&
+		    !strcmp(links[i - 1].uri, uri))
+			return i;
+	}
+
+	if (!linksfree && linkslen >= linksgc)
+		linkgc();
+	if ((i = linksfree)) {
+		linksfree = links[i - 1].next;
+	} else if (linkslen < USHRT_MAX) {
+		links = xrealloc(links, (linkslen + 1) * sizeof(*links));
+		i = ++linkslen;
+	} else {
+		fprintf(stderr, "erresc: too many hyperlinks\n");
+		return 0;
+	}
+
+	links[i - 1].id = xstrdup(id);
+	links[i - 1].uri = xstrdup(uri);
+	links[i - 1].next = linksbucket[h];
+	linksbucket[h] = i;
+
+	return i;
+}
+
+uint
+linkhash(char *id, char *uri)
+{
+	uint h = 5381;
+
+	while (*id)
+		h = h * 33 + (uchar)*id++;
+	while (*uri)
+		h = h * 33 + (uchar)*uri++;
+
+	return h % LINK_BUCKETS;
+}
+
+/*
+ * Frees the links no glyph on the screens or in the history, nor the
+ * cursor, points at. The table then grows to twice the links left before
+ * the next collection, so printing new links stays cheap.
+ */
+void
+linkgc(void)
+{
+	char *used;
+	int i, x, y, n = 0;
+	uint h;
+
+	used = xmalloc(linkslen + 1);
+	memset(used, 0, linkslen + 1);
+
+	for (x = 0; x < term.col; x++) {
+		for (y = 0; y < term.row; y++) {
+			used[term.line[y][x].link] = 1;
+			used[term.alt[y][x].link] = 1;
+		}
+		for (y = 0; y < histsize; y++)
+			used[term.hist[y][x].link] = 1;
+	}
+	used[term.c.attr.link] = 1;
+	used[cursorstorage[0].attr.link] = 1;
+	used[cursorstorage[1].attr.link] = 1;
+	used[hoverlink] = 1;
+
+	memset(linksbucket, 0, sizeof(linksbucket));
+	linksfree = 0;
+	for (i = linkslen; i > 0; i--) {
+		if (links[i - 1].id && !used[i]) {
+			free(links[i - 1].id);
+			free(links[i - 1].uri);
+			links[i - 1].id = links[i - 1].uri = NULL;
+		}
+		if (links[i - 1].id) {
+			h = linkhash(links[i - 1].id, links[i - 1].uri);
+			links[i - 1].next = linksbucket[h];
+			linksbucket[h] = i;
+			n++;
+		} else {
+			links[i - 1].next = linksfree;
+			linksfree = i;
+		}
+	}
+	free(used);
+
+	linksgc = MIN(MAX(2 * n, LINK_GC_MIN), USHRT_MAX);
+}
+
+char *
+linkuri(ushort link)
+{
+	return BETWEEN(link, 1, linkslen) ? links[link - 1].uri : NULL;
+}
+
+/*
+ * OSC 133 ; A|B|C|D [; ...]
+ * Marks the cell under the cursor as the start of the prompt (A), of the
+ * command line (B), of the command's output (C) or as the point the command
+ * finished (D).
+ */
+void
+osc133handle(char c)
+{
+	ushort mark;
+
+	switch (c) {
+	case 'A':
+		mark = ATTR_PROMPT;
+		break;
+	case 'B':
+		mark = ATTR_INPUT;
+		break;
+	case 'C':
+		mark = ATTR_OUTPUT;
+		break;
+	case 'D':
+		mark = ATTR_DONE;
+		break;
+	default:
+		fprintf(stderr, "erresc: unknown OSC 133 mark %c\n", c);
+		return;
+	}
+	term.line[term.c.y][term.c.x].mode |= mark;
+}
+
+void
+dcshandle(void)
+{
+	char *p = strescseq.buf;
+
+	strescseq.buf[strescseq.len] = '\0';
+	p += strspn(p, "0123456789;");
+	if (*p == 'q') {
+		/* end the parameters there so P2 isn't looked for in the data */
+		*p = '\0';
+		sixelhandle(strescseq.buf, p + 1);
+	}
+}
+
+/*
+ * DCS P1 ; P2 ; P3 q <sixel data> ST
+ * Only P2 is used: 1 leaves the pixels that are not drawn transparent,
+ * anything else fills them with the default background.
+ */
+void
+sixelhandle(char *params, char *data)
+{
+	static const uchar vt340[16][3] = {
+		{  0,  0,  0 }, { 20, 20, 80 }, { 80, 13, 13 }, { 20, 80, 20 },
+		{ 80, 20, 80 }, { 20, 80, 80 }, { 80, 80, 20 }, { 53, 53, 53 },
+		{ 26, 26, 26 }, { 33, 33, 60 }, { 60, 26, 26 }, { 33, 60, 33 },
+		{ 60, 33, 60 }, { 33, 60, 60 }, { 60, 60, 33 }, { 80, 80, 80 },
+	};
+	uint32_t pal[SIXEL_COLORS], *pixels = NULL, color, bg;
+	int args[5], i, n, c, rep, bits, transparent = 0;
+	int x = 0, y = 0, w = 0, h = 0, rw = 0, rh = 0, pw = 0, ph = 0;
+	char *p;
+	ushort img;
+
+	if ((p = strchr(params, ';')))
+		transparent = atoi(p + 1) == 1;
+
+	for (i = 0; i < LEN(pal); i++) {
+		pal[i] = i < LEN(vt340) ?
+			0xff000000 | (vt340[i][0] * 255 / 100) << 16 |
+			(vt340[i][1] * 255 / 100) << 8 | vt340[i][2] * 255 / 100
+			: 0xff000000;
+	}
+	color = pal[0];
+
+	for (p = data; (c = *p) != '\0'; ) {
+		p++;
+		rep = 1;
+		switch (c) {
+		case '"': /* raster attributes: Pan ; Pad ; Ph ; Pv */
+			if (sixelargs(&p, args, 4) == 4) {
+				rw = MIN(args[2], IMAGE_MAX);
+				rh = MIN(args[3], IMAGE_MAX);
+			}
+			continue;
+		case '#': /* color: Pc [; Pu ; Px ; Py ; Pz] */
+			n = sixelargs(&p, args, 5);
+			args[0] %= SIXEL_COLORS;
+			if (n == 5 && args[1] == 1) {
+				pal[args[0]] = sixelhls(args[2], args[3],
+				                        args[4]);
+			} else if (n == 5 && args[1] == 2) {
+				pal[args[0]] = 0xff000000 |
+					MIN(args[2], 100) * 255 / 100 << 16 |
+					MIN(args[3], 100) * 255 / 100 << 8 |
+					MIN(args[4], 100) * 255 / 100;
+			}
+			color = pal[args[0]];
+			continue;
+		case '$': /* graphics carriage return */
+			x = 0;
+			continue;
+		case '-': /* graphics new line */
+			x = 0;
+			y += 6;
+			continue;
+		case '!': /* repeat: Pn <sixel> */
+			sixelargs(&p, &rep, 1);
+			DEFAULT(rep, 1);
+			if (!BETWEEN(*p, '?', '~'))
+				continue;
+			c = *p++;
+			break;
+		default:
+			if (!BETWEEN(c, '?', '~'))
+				continue;
+			break;
+		}
+
+		/* x stops at IMAGE_MAX, so a huge repeat can't overflow it */
+		rep = MIN(rep, IMAGE_MAX - x);
+		if (rep == 0 || y + 6 > IMAGE_MAX) {
+			x += rep;
+			continue;
+		}
+		pixels = sixelgrow(pixels, &pw, &ph, x + rep, y + 6);
+		bits = c - '?';
+		for (i = 0; i < 6; i++) {
+			if (!(bits & (1 << i)))
+				continue;
+			for (n = 0; n < rep; n++)
+				pixels[(y + i) * pw + x + n] = color;
+			h = MAX(h, y + i + 1);
+		}
+		x += rep;
+		w = MAX(w, x);
+	}
+
+	if (rw > 0 && rh > 0) {
+		w = rw;
+		h = rh;
+		pixels = sixelgrow(pixels, &pw, &ph, w, h);
+	}
+	if (w == 0 || h == 0) {
+		free(pixels);
+		return;
+	}
+
+	if (!transparent) {
+		bg = 0xff000000 | (dc.col[defaultbg].color.red >> 8) << 16 |
+			(dc.col[defaultbg].color.green >> 8) << 8 |
+			dc.col[defaultbg].color.blue >> 8;
+		for (i = 0; i < pw * ph; i++) {
+			if (!pixels[i])
+				pixels[i] = bg;
+		}
+	}
+
+	if ((img = imagenew(pixels, w, h, pw))) {
+		tputimage(img);
+		tnewline(0);
+	}
+	free(pixels);
+}
+
+/*
+ * Reads ';' separated numbers, storing up to max of them in args, and
+ * returns how many were stored.
+ */
+int
+sixelargs(char **p, int *args, int max)
+{
+	char *end;
+	long v;
+	int n = 0;
+
+	for (;;) {
+		v = strtol(*p, &end, 10);
+		if (n < max)
+			args[n++] = MIN(MAX(v, 0), INT_MAX);
+		*p = end;
+		if (**p != ';')
+			return n;
+		(*p)++;
+	}
+}
+
+/* sixel HLS puts blue at 0 degrees */
+uint32_t
+sixelhls(int hue, int lum, int sat)
+{
+	double h = (hue % 360 + 240) % 360 / 360.0;
+	double l = MIN(lum, 100) / 100.0, s = MIN(sat, 100) / 100.0;
+	double q = l < 0.5 ? l * (1 + s) : l + s - l * s, p = 2 * l - q, t, v;
+	uint32_t rgb = 0xff000000;
+	int i;
+
+	for (i = 0; i < 3; i++) {
+		t = h + (1 - i) / 3.0;
+		if (t < 0)
+			t += 1;
+		if (t > 1)
+			t -= 1;
+		if (t < 1 / 6.0)
+			v = p + (q - p) * 6 * t;
+		else if (t < 0.5)
+			v = q;
+		else if (t < 2 / 3.0)
+			v = p + (q - p) * (2 / 3.0 - t) * 6;
+		else
+			v = p;
+		rgb |= (uint32_t)(v * 255 + 0.5) << (16 - 8 * i);
+	}
+	return rgb;
+}
+
+/* Makes the pixel buffer at least w by h, doubling it as needed */
+uint32_t *
+sixelgrow(uint32_t *pixels, int *pw, int *ph, int w, int h)
+{
+	uint32_t *new;
+	int nw = MAX(*pw, 64), nh = MAX(*ph, 64), y;
+
+	if (pixels && w <= *pw && h <= *ph)
+		return pixels;
+
+	while (nw < w)
+		nw *= 2;
+	while (nh < h)
+		nh *= 2;
+	nw = MIN(nw, IMAGE_MAX);
+	nh = MIN(nh, IMAGE_MAX);
+
+	new = xmalloc(nw * nh * sizeof(*new));
+	memset(new, 0, nw * nh * sizeof(*new));
+	for (y = 0; y < *ph; y++)
+		memcpy(&new[y * nw], &pixels[y * *pw], *pw * sizeof(*new));
+	free(pixels);
+	*pw = nw;
+	*ph = nh;
+	return new;
+}
+
+/*
+ * CSI ? Pi ; Pa ; Pv S
+ * Sizes can be read (Pa 1, 2 and 4) but not set.
+ */
+void
+xtsmgraphics(int item, int action)
+{
+	char buf[40];
+	int len;
+
+	if (item != 1 && item != 2) {
+		len = snprintf(buf, sizeof(buf), "\033[?%d;1;0S", item);
+	} else if (!BETWEEN(action, 1, 4) || action == 3) {
+		len = snprintf(buf, sizeof(buf), "\033[?%d;%dS", item,
+		               action == 3 ? 3 : 2);
+	} else if (item == 1) {
+		len = snprintf(buf, sizeof(buf), "\033[?1;0;%dS", SIXEL_COLORS);
+	} else if (action == 4) {
+		len = snprintf(buf, sizeof(buf), "\033[?2;0;%d;%dS",
+		               IMAGE_MAX, IMAGE_MAX);
+	} else {
+		len = snprintf(buf, sizeof(buf), "\033[?2;0;%d;%dS",
+		               MIN(xw.w, IMAGE_MAX), MIN(xw.h, IMAGE_MAX));
+	}
+	ttywrite(buf, len);
+}
+
+/*
+ * Stores a w by h ARGB image whose rows are stride pixels apart, returning
+ * its index + 1, or 0 if it couldn't be stored.
+ */
+ushort
+imagenew(uint32_t *pixels, int w, int h, int stride)
+{
+	XImage *ximg;
+	GC gc;
+	Image *im;
+	int i;
+
+	imagegc();
+	for (i = 0; i < term.imageslen && term.images[i].pic; i++)
+		;
+	if (i == term.imageslen) {
+		if (term.imageslen >= USHRT_MAX) {
+			fprintf(stderr, "erresc: too many images\n");
+			return 0;
+		}
+		term.images = xrealloc(term.images,
+		                       ++term.imageslen * sizeof(*term.images));
+	}
+	im = &term.images[i];
+
+	im->w = w;
+	im->h = h;
+	im->id = im->pid = 0;
+	im->cw = xw.cw;
+	im->ch = xw.ch;
+	im->pix = XCreatePixmap(xw.dpy, xw.win, w, h, 32);
+	ximg = XCreateImage(xw.dpy, xw.vis, 32, ZPixmap, 0, (char *)pixels,
+	                    w, h, 32, stride * sizeof(*pixels));
+	gc = XCreateGC(xw.dpy, im->pix, 0, NULL);
+	XPutImage(xw.dpy, im->pix, gc, ximg, 0, 0, 0, 0, w, h);
+	XFreeGC(xw.dpy, gc);
+	ximg->data = NULL;
+	XDestroyImage(ximg);
+	im->pic = XRenderCreatePicture(xw.dpy, im->pix,
+		XRenderFindStandardFormat(xw.dpy, PictStandardARGB32), 0, NULL);
+
+	return i + 1;
+}
+
+/* Frees the images no glyph on the screens or in the history points at */
+void
+imagegc(void)
+{
+	char *used;
+	int i, x, y;
+
+	if (!term.imageslen)
+		return;
+	used = xmalloc(term.imageslen);
+	memset(used, 0, term.imageslen);
+
+	for (x = 0; x < term.col; x++) {
+		for (y = 0; y < term.row; y++) {
+			if (term.line[y][x].img)
+				used[term.line[y][x].img - 1] = 1;
+			if (term.alt[y][x].img)
+				used[term.alt[y][x].img - 1] = 1;
+		}
+		for (y = 0; y < histsize; y++) {
+			if (term.hist[y][x].img)
+				used[term.hist[y][x].img - 1] = 1;
+		}
+	}
+
+	for (i = 0; i < term.imageslen; i++) {
+		if (used[i] || !term.images[i].pic)
+			continue;
+		XRenderFreePicture(xw.dpy, term.images[i].pic);
+		XFreePixmap(xw.dpy, term.images[i].pix);
+		term.images[i].pic = 0;
+	}
+	free(used);
+}
+
+/*
+ * Covers the cells from the cursor with the image, scrolling as text
+ * would, and leaves the cursor on its last line.
+ */
+void
+tputimage(ushort img)
+{
+	Image *im = &term.images[img - 1];
+	int cols = DIVCEIL(im->w, im->cw), rows = DIVCEIL(im->h, im->ch);
+	int x, y;
+	Glyph *gp;
+
+	for (y = 0; y < rows; y++) {
+		if (y > 0)
+			tnewline(0);
+		for (x = 0; x < cols && term.c.x + x < term.col; x++) {
+			tsetchar(' ', &term.c.attr, term.c.x + x, term.c.y);
+			gp = &term.line[term.c.y][term.c.x + x];
+			gp->img = img;
+			gp->imgx = x;
+			gp->imgy = y;
+		}
+	}
+}
+
+/* Draws the part of the glyph's image that falls in its cell */
+void
+xdrawimage(Glyph g, int x, int y)
+{
+	Image *im;
+	Picture dst;
+
+	if (!BETWEEN(g.img, 1, term.imageslen))
+		return;
+	if (!(im = &term.images[g.img - 1])->pic)
+		return;
+	if (!(dst = XftDrawPicture(xw.draw)))
+		return;
+
+	XRenderComposite(xw.dpy, PictOpOver, im->pic, None, dst,
+	                 g.imgx * im->cw, g.imgy * im->ch, 0, 0,
+	                 borderpx + x * xw.cw, borderpx + y * xw.ch,
+	                 MIN(im->cw, xw.cw), MIN(im->ch, xw.ch));
+}
+
+void
+apchandle(void)
+{
+	strescseq.buf[strescseq.len] = '\0';
+	if (strescseq.buf[0] == 'G')
+		kittyhandle(strescseq.buf + 1);
+}
+
+/*
+ * ESC _ G <key>=<value>[,<key>=<value>...][;<base64 payload>] ESC \
+ * A payload sent with m=1 continues in the following commands, which only
+ * carry the m key, until one has m=0.
+ */
+void
+kittyhandle(char *s)
+{
+	static KittyCmd cmd;
+	KittyCmd next;
+	char *payload;
+	size_t len;
+
+	if ((payload = strchr(s, ';')))
+		*payload++ = '\0';
+	else
+		payload = "";
+
+	if (cmd.m) {
+		memset(&next, 0, sizeof(next));
+		kittyparse(s, &next);
+		cmd.m = next.m;
+	} else {
+		kittyparse(s, &cmd);
+	}
+
+	len = strlen(payload);
+	if (cmd.len + len > STR_BUF_MAX) {
+		kittyreply(&cmd, "EFBIG:image data is too large");
+		cmd.m = 0;
+	} else {
+		cmd.data = xrealloc(cmd.data, cmd.len + len + 1);
+		memcpy(cmd.data + cmd.len, payload, len + 1);
+		cmd.len += len;
+		if (!cmd.m)
+			kittyrun(&cmd);
+	}
+
+	if (!cmd.m) {
+		free(cmd.data);
+		memset(&cmd, 0, sizeof(cmd));
+	}
+}
+
+void
+kittyparse(char *s, KittyCmd *cmd)
+{
+	char *p, *v, key;
+	long n;
+
+	for (p = strtok(s, ","); p; p = strtok(NULL, ",")) {
+		if (p[0] == '\0' || p[1] != '=')
+			continue;
+		key = p[0];
+		v = p + 2;
+		n = strtol(v, NULL, 10);
+		switch (key) {
+		case 'a': cmd->a = *v; break;
+		case 't': cmd->t = *v; break;
+		case 'o': cmd->o = *v; break;
+		case 'd': cmd->d = *v; break;
+		case 'f': cmd->f = n; break;
+		case 'i': cmd->i = n; break;
+		case 'I': cmd->I = n; break;
+		case 'p': cmd->p = n; break;
+		case 'q': cmd->q = n; break;
+		case 'm': cmd->m = n; break;
+		case 'C': cmd->C = n; break;
+		case 's': cmd->s = n; break;
+		case 'v': cmd->v = n; break;
+		case 'S': cmd->S = n; break;
+		case 'O': cmd->O = n; break;
+		case 'x': cmd->x = n; break;
+		case 'y': cmd->y = n; break;
+		case 'w': cmd->w = n; break;
+		case 'h': cmd->h = n; break;
+		case 'c': cmd->c = n; break;
+		case 'r': cmd->r = n; break;
+		}
+	}
+}
+
+void
+kittyrun(KittyCmd *cmd)
+{
+	Graphic *g, tmp;
+	char *err = NULL;
+	uint id;
+	int i;
+
+	switch (cmd->a ? cmd->a : 't') {
+	case 'q': /* query support, nothing is kept */
+		if ((tmp.pixels = kittyload(cmd, &tmp.w, &tmp.h, &err)))
+			free(tmp.pixels);
+		kittyreply(cmd, err ? err : "OK");
+		return;
+	case 't': /* transmit */
+	case 'T': /* transmit and display */
+		if (!(tmp.pixels = kittyload(cmd, &tmp.w, &tmp.h, &err))) {
+			kittyreply(cmd, err);
+			return;
+		}
+		if (!cmd->i && cmd->I) {
+			for (id = 0, i = 0; i < term.graphicslen; i++)
+				id = MAX(id, term.graphics[i].id);
+			cmd->i = id + 1;
+		}
+		if (cmd->a == 'T')
+			kittyplace(cmd, &tmp, &err);
+		if (!cmd->i) {
+			free(tmp.pixels);
+			return;
+		}
+		tmp.id = cmd->i;
+		tmp.num = cmd->I;
+		/* a retransmitted image counts as the newest */
+		if ((g = kittyfind(cmd->i, 0))) {
+			free(g->pixels);
+			i = g - term.graphics;
+			memmove(g, g + 1, (--term.graphicslen - i) * sizeof(*g));
+		}
+		kittyquota((size_t)tmp.w * tmp.h * sizeof(*tmp.pixels));
+		term.graphics = xrealloc(term.graphics,
+			++term.graphicslen * sizeof(*term.graphics));
+		term.graphics[term.graphicslen - 1] = tmp;
+		kittyreply(cmd, err ? err : "OK");
+		return;
+	case 'p': /* display */
+		if (!(g = kittyfind(cmd->i, cmd->I))) {
+			kittyreply(cmd, "ENOENT:no such image");
+			return;
+		}
+		cmd->i = g->id;
+		kittyplace(cmd, g, &err);
+		kittyreply(cmd, err ? err : "OK");
+		return;
+	case 'd': /* delete */
+		kittydelete(cmd);
+		return;
+	default:
+		kittyreply(cmd, "EINVAL:unsupported action");
+		return;
+	}
+}
+
+/* Replies unless q silences it, which needs an i or I key to refer to */
+void
+kittyreply(KittyCmd *cmd, const char *msg)
+{
+	char buf[256];
+	int len;
+
+	if (!cmd->i && !cmd->I)
+		return;
+	if (cmd->q >= 2 || (cmd->q == 1 && !strcmp(msg, "OK")))
+		return;
+
+	len = snprintf(buf, sizeof(buf), "\033_Gi=%u", cmd->i);
+	if (cmd->I)
+		len += snprintf(buf + len, sizeof(buf) - len, ",I=%u", cmd->I);
+	if (cmd->p)
+		len += snprintf(buf + len, sizeof(buf) - len, ",p=%u", cmd->p);
+	len += snprintf(buf + len, sizeof(buf) - len, ";%s\033\\", msg);
+	ttywrite(buf, MIN(len, sizeof(buf) - 1));
+}
+
+/* Finds a transmitted image by id, or by number if id is 0 */
+Graphic *
+kittyfind(uint id, uint num)
+{
+	int i;
+
+	for (i = term.graphicslen - 1; i >= 0; i--) {
+		if (id ? term.graphics[i].id == id :
+		   num && term.graphics[i].num == num)
+			return &term.graphics[i];
+	}
+	return NULL;
+}
+
+/*
+ * Frees the oldest transmitted images until size more bytes fit in
+ * KITTY_QUOTA, starting with the ones that aren't placed anywhere.
+ */
+void
+kittyquota(size_t size)
+{
+	Graphic *g;
+	size_t total = size;
+	int i, j, placed, pass;
+
+	imagegc();
+	for (j = 0; j < term.graphicslen; j++) {
+		g = &term.graphics[j];
+		total += (size_t)g->w * g->h * sizeof(*g->pixels);
+	}
+
+	for (pass = 0; pass < 2; pass++) {
+		for (j = 0; total > KITTY_QUOTA && j < term.graphicslen; ) {
+			g = &term.graphics[j];
+			for (placed = 0, i = 0; !pass && i < term.imageslen; i++)
+				placed |= term.images[i].pic && term.images[i].id == g->id;
+			if (placed) {
+				j++;
+				continue;
+			}
+			total -= (size_t)g->w * g->h * sizeof(*g->pixels);
+			free(g->pixels);
+			memmove(g, g + 1, (--term.graphicslen - j) * sizeof(*g));
+		}
+	}
+}
+
+/*
+ * Returns the premultiplied ARGB pixels of the transmitted image, or NULL
+ * with err set to the reply. Only direct and local file transmission are
+ * supported.
+ */
+uint32_t *
+kittyload(KittyCmd *cmd, int *w, int *h, char **err)
+{
+	uchar *data, *raw, *p;
+	uint32_t *pixels;
+	size_t len, n;
+	uLongf rawlen;
+	int i, bpp, z;
+
+	if (!(data = (uchar *)kittyread(cmd, &len, err)))
+		return NULL;
+
+	if (cmd->o == 'z') {
+		rawlen = MAX(len * 4, 4096);
+		for (;;) {
+			raw = xmalloc(rawlen);
+			z = uncompress(raw, &rawlen, data, len);
+			if (z != Z_BUF_ERROR || rawlen >= STR_BUF_MAX * 4)
+				break;
+			free(raw);
+			rawlen *= 2;
+		}
+		free(data);
+		if (z != Z_OK) {
+			free(raw);
+			*err = "EINVAL:bad zlib data";
+			return NULL;
+		}
+		data = raw;
+		len = rawlen;
+	}
+
+	switch (cmd->f ? cmd->f : 32) {
+	case 100:
+		pixels = kittypng(data, len, w, h, err);
+		free(data);
+		return pixels;
+	case 24:
+	case 32:
+		bpp = (cmd->f ? cmd->f : 32) / 8;
+		break;
+	default:
+		free(data);
+		*err = "EINVAL:unsupported format";
+		return NULL;
+	}
+
+	if (!BETWEEN(cmd->s, 1, IMAGE_MAX) || !BETWEEN(cmd->v, 1, IMAGE_MAX)) {
+		free(data);
+		*err = "EINVAL:bad image size";
+		return NULL;
+	}
+	n = (size_t)cmd->s * cmd->v;
+	if (len < n * bpp) {
+		free(data);
+		*err = "ENODATA:not enough image data";
+		return NULL;
+	}
+
+	pixels = xmalloc(n * sizeof(*pixels));
+	for (i = 0, p = data; i < n; i++, p += bpp) {
+		pixels[i] = bpp == 3 ? 0xff000000 | p[0] << 16 | p[1] << 8 | p[2]
+			: kittyargb(p[0], p[1], p[2], p[3]);
+	}
+	free(data);
+	*w = cmd->s;
+	*h = cmd->v;
+
+	return pixels;
+}
+
+/*
+ * Whether t=t may delete path: it has to name a file in one of the temporary
+ * directories, as the spec asks, after resolving any links.
+ */
+int
+kittytemp(const char *path)
+{
+	const char *dirs[] = { "/tmp", "/dev/shm", getenv("TMPDIR") };
+	char real[PATH_MAX], dir[PATH_MAX];
+	size_t n;
+	int i;
+
+	if (!strstr(path, "tty-graphics-protocol") || !realpath(path, real))
+		return 0;
+
+	for (i = 0; i < LEN(dirs); i++) {
+		if (!dirs[i] || !realpath(dirs[i], dir))
+			continue;
+		n = strlen(dir);
+		if (!strncmp(real, dir, n) && real[n] == '/')
+			return 1;
+	}
+	return 0;
+}
+
+/* Returns the payload of the transmission as newly allocated bytes */
+char *
+kittyread(KittyCmd *cmd, size_t *len, char **err)
+{
+	struct stat lst, st;
+	char *path, *data;
+	ssize_t n;
+	int fd;
+
+	switch (cmd->t ? cmd->t : 'd') {
+	case 'd':
+		if (!(data = base64dec(cmd->data, len)))
+			*err = "EINVAL:bad base64 data";
+		return data;
+	case 'f':
+	case 't':
+		break;
+	default:
+		*err = "EBADF:only direct and file transmission are supported";
+		return NULL;
+	}
+
+	if (!(path = base64dec(cmd->data, NULL))) {
+		*err = "EINVAL:bad base64 data";
+		return NULL;
+	}
+	/* never block on a fifo or device, or follow a planted link */
+	fd = -1;
+	if (lstat(path, &lst) < 0 || !S_ISREG(lst.st_mode) ||
+	   (fd = open(path, O_RDONLY|O_NONBLOCK)) < 0 ||
+	   fstat(fd, &st) < 0 || !S_ISREG(st.st_mode) ||
+	   st.st_dev != lst.st_dev || st.st_ino != lst.st_ino) {
+		if (fd >= 0)
+			close(fd);
+		free(path);
+		*err = "EBADF:cannot read file";
+		return NULL;
+	}
+
+	*len = cmd->S ? cmd->S : st.st_size - MIN(cmd->O, st.st_size);
+	*len = MIN(*len, STR_BUF_MAX);
+	data = xmalloc(*len + 1);
+	n = pread(fd, data, *len, cmd->O);
+	close(fd);
+	/* temporary files may only be removed if they are clearly meant to be */
+	if (cmd->t == 't' && kittytemp(path))
+		unlink(path);
+	free(path);
+
+	if (n < 0) {
+		free(data);
+		*err = "EBADF:cannot read file";
+		return NULL;
+	}
+	*len = n;
+
+	return data;
+}
+
+uint32_t *
+kittypng(uchar *data, size_t len, int *w, int *h, char **err)
 {
+	png_image png;
+	uint32_t *pixels;
+	uchar *p;
 	int i;
-	uint c;
 
-	printf("ESC[");
-	for (i = 0; i < csiescseq.len; i++) {
-		c = csiescseq.buf[i] & 0xff;
-		if (isprint(c)) {
-			putchar(c);
-		} else if (c == '\n') {
-			printf("(\\n)");
-		} else if (c == '\r') {
-			printf("(\\r)");
-		} else if (c == 0x1b) {
-			printf("(\\e)");
-		} else {
-			printf("(%02x)", c);
-		}
+	memset(&png, 0, sizeof(png));
+	png.version = PNG_IMAGE_VERSION;
+	if (!png_image_begin_read_from_memory(&png, data, len)) {
+		*err = "EINVAL:bad png data";
+		return NULL;
 	}
-	putchar('\n');
+	if (png.width > IMAGE_MAX || png.height > IMAGE_MAX) {
+		png_image_free(&png);
+		*err = "EINVAL:bad image size";
+		return NULL;
+	}
+
+	png.format = PNG_FORMAT_RGBA;
+	pixels = xmalloc(PNG_IMAGE_SIZE(png));
+	if (!png_image_finish_read(&png, NULL, pixels, 0, NULL)) {
+		free(pixels);
+		*err = "EINVAL:bad png data";
+		return NULL;
+	}
+
+	*w = png.width;
+	*h = png.height;
+	for (i = 0, p = (uchar *)pixels; i < *w * *h; i++, p += 4)
+		pixels[i] = kittyargb(p[0], p[1], p[2], p[3]);
+
+	return pixels;
 }
 
-void
-csireset(void)
+uint32_t
+kittyargb(uchar r, uchar g, uchar b, uchar a)
 {
-	memset(&csiescseq, 0, sizeof(csiescseq));
+	return (uint32_t)a << 24 | (r * a / 255) << 16 | (g * a / 255) << 8
+		| b * a / 255;
+}
+
+/*
+ * Displays the x, y, w, h part of the image over c columns and r rows
+ * from the cursor, scaling it if either is given. The cursor is moved past
+ * the image unless C is 1.
+ */
+int
+kittyplace(KittyCmd *cmd, Graphic *g, char **err)
+{
+	XTransform xf;
+	Image *im;
+	int x, y, w, h, cols, rows, ox = term.c.x, oy = term.c.y;
+	ushort img;
+
+	if (!BETWEEN(cmd->x, 0, g->w - 1) || !BETWEEN(cmd->y, 0, g->h - 1) ||
+	    cmd->w < 0 || cmd->h < 0 || !BETWEEN(cmd->c, 0, term.col) ||
+	    !BETWEEN(cmd->r, 0, term.row)) {
+		*err = "EINVAL:placement out of range";
+		return 0;
+	}
+	x = cmd->x;
+	y = cmd->y;
+	w = cmd->w ? MIN(cmd->w, g->w - x) : g->w - x;
+	h = cmd->h ? MIN(cmd->h, g->h - y) : g->h - y;
+
+	/* the side that isn't given keeps the aspect, up to the screen */
+	cols = cmd->c;
+	rows = cmd->r;
+	if (cols && !rows)
+		rows = MIN(DIVCEIL(cols * xw.cw * h / w, xw.ch), term.row);
+	else if (rows && !cols)
+		cols = MIN(DIVCEIL(rows * xw.ch * w / h, xw.cw), term.col);
+
+	if (cmd->i && cmd->p)
+		kittyerase(cmd->i, cmd->p);
+	if (!(img = imagenew(g->pixels + y * g->w + x, w, h, g->w))) {
+		*err = "ENOMEM:cannot create the image";
+		return 0;
+	}
+	im = &term.images[img - 1];
+	im->id = cmd->i;
+	im->pid = cmd->p;
+
+	if (cols && rows) {
+		im->w = cols * xw.cw;
+		im->h = rows * xw.ch;
+		xf = (XTransform){{
+			{ XDoubleToFixed((double)w / im->w), 0, 0 },
+			{ 0, XDoubleToFixed((double)h / im->h), 0 },
+			{ 0, 0, XDoubleToFixed(1) },
+		}};
+		XRenderSetPictureTransform(xw.dpy, im->pic, &xf);
+		XRenderSetPictureFilter(xw.dpy, im->pic, FilterBilinear,
+		                        NULL, 0);
+	}
+	cols = DIVCEIL(im->w, im->cw);
+	rows = DIVCEIL(im->h, im->ch);
+
+	tputimage(img);
+	if (cmd->C == 1)
+		tmoveto(ox, oy - (oy + rows - 1 - term.c.y));
+	else
+		tmoveto(ox + cols, term.c.y);
+	return 1;
 }
 
+/* Removes the placement's image from the screen */
 void
-strhandle(void)
+kittyerase(uint id, uint pid)
 {
-	char *p = NULL;
-	int j, narg, par;
+	Glyph *gp;
+	int x, y;
 
-	term.esc &= ~(ESC_STR_END|ESC_STR);
-	strparse();
-	par = (narg = strescseq.narg) ? atoi(strescseq.args[0]) : 0;
+	for (y = 0; y < term.row; y++) {
+		for (x = 0; x < term.col; x++) {
+			gp = &term.line[y][x];
+			if (!gp->img || term.images[gp->img - 1].id != id ||
+			   term.images[gp->img - 1].pid != pid)
+				continue;
+			gp->img = 0;
+			term.dirty[y] = 1;
+		}
+	}
+}
 
-	switch (strescseq.type) {
-	case ']': /* OSC -- Operating System Command */
-		switch (par) {
-		case 0:
-		case 1:
-		case 2:
-			if (narg > 1)
-				xsettitle(strescseq.args[1]);
-			return;
-		case 4: /* color set */
-			if (narg < 3)
+/*
+ * Removes the placements chosen by d from the screen. The upper case
+ * forms also free the data of the images they belong to.
+ */
+void
+kittydelete(KittyCmd *cmd)
+{
+	char *hit;
+	Graphic *g;
+	Image *im;
+	int i, j, x, y, match;
+	char d = cmd->d ? cmd->d : 'a';
+
+	if ((d == 'n' || d == 'N') && (g = kittyfind(0, cmd->I)))
+		cmd->i = g->id;
+
+	hit = xmalloc(term.imageslen + 1);
+	memset(hit, 0, term.imageslen + 1);
+	for (y = 0; y < term.row; y++) {
+		for (x = 0; x < term.col; x++) {
+			if (!(i = term.line[y][x].img))
+				continue;
+			im = &term.images[i - 1];
+			switch (d | 0x20) {
+			case 'a':
+				match = 1;
+				break;
+			case 'i':
+			case 'n':
+				match = cmd->i && im->id == cmd->i &&
+					(!cmd->p || im->pid == cmd->p);
+				break;
+			case 'c':
+				match = x == term.c.x && y == term.c.y;
+				break;
+			case 'p':
+				match = x == cmd->x - 1 && y == cmd->y - 1;
+				break;
+			case 'x':
+				match = x == cmd->x - 1;
+				break;
+			case 'y':
+				match = y == cmd->y - 1;
+				break;
+			default:
+				match = 0;
 				break;
-			p = strescseq.args[2];
-			/* FALLTHROUGH */
-		case 104: /* color reset, here p = NULL */
-			j = (narg > 1) ? atoi(strescseq.args[1]) : -1;
-			if (xsetcolorname(j, p)) {
-				fprintf(stderr, "erresc: invalid color %s\n", p);
-			} else {
-				/*
-				 * TODO if defaultbg color is changed, borders
-				 * are dirty
-				 */
-				redraw();
 			}
-			return;
+			if (match)
+				hit[i - 1] = 1;
 		}
-		break;
-	case 'k': /* old title set compatibility */
-		xsettitle(strescseq.args[0]);
-		return;
-	case 'P': /* DCS -- Device Control String */
-	case '_': /* APC -- Application Program Command */
-	case '^': /* PM -- Privacy Message */
-		return;
 	}
 
-	fprintf(stderr, "erresc: unknown str ");
-	strdump();
+	for (y = 0; y < term.row; y++) {
+		for (x = 0; x < term.col; x++) {
+			if ((i = term.line[y][x].img) && hit[i - 1]) {
+				term.line[y][x].img = 0;
+				term.dirty[y] = 1;
+			}
+		}
+	}
+
+	for (j = 0; BETWEEN(d, 'A', 'Z') && j < term.graphicslen; ) {
+		g = &term.graphics[j];
+		match = d == 'A' || (cmd->i && g->id == cmd->i);
+		for (i = 0; !match && i < term.imageslen; i++)
+			match = hit[i] && term.images[i].id == g->id;
+		if (!match) {
+			j++;
+			continue;
+		}
+		free(g->pixels);
+		memmove(g, g + 1, (--term.graphicslen - j) * sizeof(*g));
+	}
+	free(hit);
+	imagegc();
 }
 
 void
@@ -2407,7 +5015,10 @@ strdump(void)
 void
 strreset(void)
 {
-	memset(&strescseq, 0, sizeof(strescseq));
+	strescseq = (STREscape){
+		.buf = xrealloc(strescseq.buf, STR_BUF_SIZ),
+		.siz = STR_BUF_SIZ,
+	};
 }
 
 void
@@ -2514,18 +5125,159 @@ techo(Rune u)
 	tputc(u);
 }
 
-void
+/*
+ * Designates the charset of ESC ( ) * + or, for the 96 character sets,
+ * ESC - . /. Returns 0 while an intermediate, like the % of ESC ( % 5,
+ * still needs its final character.
+ */
+int
 tdeftran(char ascii)
 {
-	static char cs[] = "0B";
-	static int vcs[] = {CS_GRAPHIC0, CS_USA};
+	static char cs[] = "0BA<>4C5RfQ9KYE6`ZH7=";
+	static int vcs[] = {
+		CS_GRAPHIC0, CS_USA, CS_UK, CS_MULTI, CS_TECH, CS_DUT,
+		CS_FIN, CS_FIN, CS_FRE, CS_FRE, CS_FRECAN, CS_FRECAN,
+		CS_GER, CS_ITA, CS_NOR, CS_NOR, CS_NOR, CS_SPA, CS_SWE,
+		CS_SWE, CS_SWI
+	};
+	int g = term.icharset & 3, inter = term.icharset & 8, set = -1;
 	char *p;
 
-	if ((p = strchr(cs, ascii)) == NULL) {
-		fprintf(stderr, "esc unhandled charset: ESC ( %c\n", ascii);
+	/* remember the intermediate in the 8 bit */
+	if (ascii == '%' && !inter) {
+		term.icharset |= 8;
+		return 0;
+	}
+
+	if (term.icharset & 4) {
+		if (ascii == 'A' && !inter)
+			set = CS_LATIN1;
+	} else if (inter) {
+		if (ascii == '5')
+			set = CS_MULTI;
+		else if (ascii == '6')
+			set = CS_POR;
+	} else if ((p = strchr(cs, ascii))) {
+		set = vcs[p - cs];
+	}
+
+	if (set < 0) {
+		fprintf(stderr, "esc unhandled charset: ESC %c %s%c\n",
+			"()*+,-./"[term.icharset & 7], inter ? "%" : "", ascii);
 	} else {
-		term.trantbl[term.icharset] = vcs[p - cs];
+		term.trantbl[g] = set;
+	}
+	return 1;
+}
+
+/*
+ * Maps u through the charset invoked into GL, honouring single shifts.
+ * Input is always decoded as UTF-8, so anything above ASCII is a real
+ * character rather than a GR byte and is left alone.
+ */
+Rune
+ttranslate(Rune u)
+{
+	static char *vt100_0[62] = { /* 0x41 - 0x7e */
+		"↑", "↓", "→", "←", "█", "▚", "☃", /* A - G */
+		0, 0, 0, 0, 0, 0, 0, 0, /* H - O */
+		0, 0, 0, 0, 0, 0, 0, 0, /* P - W */
+		0, 0, 0, 0, 0, 0, 0, " ", /* X - _ */
+		"◆", "▒", "␉", "␌", "␍", "␊", "°", "±", /* ` - g */
+		"␤", "␋", "┘", "┐", "┌", "└", "┼", "⎺", /* h - o */
+		"⎻", "─", "⎼", "⎽", "├", "┤", "┴", "┬", /* p - w */
+		"│", "≤", "≥", "π", "≠", "£", "·", /* x - ~ */
+	};
+	static char *dectech[94] = { /* 0x21 - 0x7e */
+		"⎷", "┌", "─", "⌠", "⌡", "│", "⎡", /* ! - ' */
+		"⎣", "⎤", "⎦", "⎛", "⎝", "⎞", "⎠", "⎨", /* ( - / */
+		"⎬", "⎲", "⎳", "╲", "╱", "⌝", "⌟", "⟩", /* 0 - 7 */
+		0, 0, 0, 0, "≤", "≠", "≥", "∫", /* 8 - ? */
+		"∴", "∝", "∞", "÷", "Δ", "∇", "Φ", "Γ", /* @ - G */
+		"∼", "≃", "Θ", "×", "Λ", "⇔", "⇒", "≡", /* H - O */
+		"Π", "Ψ", 0, "Σ", 0, 0, "√", "Ω", /* P - W */
+		"Ξ", "Υ", "⊂", "⊃", "∩", "∪", "∧", "∨", /* X - _ */
+		"¬", "α", "β", "χ", "δ", "ε", "φ", "γ", /* ` - g */
+		"η", "ι", "θ", "κ", "λ", 0, "ν", "∂", /* h - o */
+		"π", "ψ", "ρ", "σ", "τ", 0, "ƒ", "ω", /* p - w */
+		"ξ", "υ", "ζ", "←", "↑", "→", "↓", /* x - ~ */
+	};
+	/* the positions national replacement sets change */
+	static char nrcspos[] = "#@[\\]^_`{|}~";
+	static struct {
+		int cs;
+		char *s[12];
+	} nrcs[] = {
+		{ CS_UK,     { "£", "@", "[", "\\", "]", "^", "_", "`", "{", "|", "}", "~" } },
+		{ CS_DUT,    { "£", "¾", "ĳ", "½", "|", "^", "_", "`", "¨", "ƒ", "¼", "´" } },
+		{ CS_FIN,    { "#", "@", "Ä", "Ö", "Å", "Ü", "_", "é", "ä", "ö", "å", "ü" } },
+		{ CS_FRE,    { "£", "à", "°", "ç", "§", "^", "_", "`", "é", "ù", "è", "¨" } },
+		{ CS_FRECAN, { "#", "à", "â", "ç", "ê", "î", "_", "ô", "é", "ù", "è", "û" } },
+		{ CS_GER,    { "#", "§", "Ä", "Ö", "Ü", "^", "_", "`", "ä", "ö", "ü", "ß" } },
+		{ CS_ITA,    { "£", "§", "°", "ç", "é", "^", "_", "ù", "à", "ò", "è", "ì" } },
+		{ CS_NOR,    { "#", "Ä", "Æ", "Ø", "Å", "Ü", "_", "ä", "æ", "ø", "å", "ü" } },
+		{ CS_POR,    { "#", "@", "Ã", "Ç", "Õ", "^", "_", "`", "ã", "ç", "õ", "~" } },
+		{ CS_SPA,    { "£", "§", "¡", "Ñ", "¿", "^", "_", "`", "°", "ñ", "ç", "~" } },
+		{ CS_SWE,    { "#", "É", "Ä", "Ö", "Å", "Ü", "_", "é", "ä", "ö", "å", "ü" } },
+		{ CS_SWI,    { "ù", "à", "é", "ç", "ê", "î", "è", "ô", "ä", "ö", "ü", "û" } },
+	};
+	char *p, *s = NULL;
+	int g, cs, i, c;
+
+	if (!BETWEEN(u, 0x20, 0x7e))
+		return u;
+	c = u;
+	g = term.sshift ? term.sshift : term.charset;
+	cs = term.trantbl[g];
+
+	switch (cs) {
+	case CS_USA:
+		return u;
+	case CS_LATIN1:
+		return u + 0x80;
+	case CS_GRAPHIC0:
+		/*
+		 * The table is proudly stolen from rxvt.
+		 */
+		if (BETWEEN(c, 0x41, 0x7e))
+			s = vt100_0[c - 0x41];
+		break;
+	case CS_TECH:
+		if (BETWEEN(c, 0x21, 0x7e))
+			s = dectech[c - 0x21];
+		break;
+	case CS_MULTI: /* DEC Supplemental, mostly Latin-1 */
+		if (!BETWEEN(c, 0x21, 0x7e))
+			break;
+		switch (c) {
+		case 0x28: /* ¤ */
+			return 0xa4;
+		case 0x57: /* Œ */
+			return 0x152;
+		case 0x5d: /* Ÿ */
+			return 0x178;
+		case 0x77: /* œ */
+			return 0x153;
+		case 0x7d: /* ÿ */
+			return 0xff;
+		}
+		return c + 0x80;
+	default:
+		/* national sets other than UK need DECNRCM */
+		if (!term.nrcs && cs != CS_UK)
+			break;
+		if (!(p = strchr(nrcspos, c)))
+			break;
+		for (i = 0; i < LEN(nrcs); i++) {
+			if (nrcs[i].cs == cs)
+				s = nrcs[i].s[p - nrcspos];
+		}
+		break;
 	}
+
+	if (s)
+		utf8decode(s, &u, UTF_SIZ);
+	return u;
 }
 
 void
@@ -2574,7 +5326,7 @@ tcontrolcode(uchar ascii)
 		tmoveto(term.c.x-1, term.c.y);
 		return;
 	case '\r':   /* CR */
-		tmoveto(0, term.c.y);
+		tmoveto(term.c.x < term.left ? 0 : term.left, term.c.y);
 		return;
 	case '\f':   /* LF */
 	case '\v':   /* VT */
@@ -2595,7 +5347,7 @@ tcontrolcode(uchar ascii)
 		break;
 	case '\033': /* ESC */
 		csireset();
-		term.esc &= ~(ESC_CSI|ESC_ALTCHARSET|ESC_TEST);
+		term.esc &= ~(ESC_CSI|ESC_ALTCHARSET|ESC_TEST|ESC_VT52Y);
 		term.esc |= ESC_START;
 		return;
 	case '\016': /* SO (LS1 -- Locking shift 1) */
@@ -2633,8 +5385,10 @@ tcontrolcode(uchar ascii)
 	case 0x8b:   /* TODO: PLD */
 	case 0x8c:   /* TODO: PLU */
 	case 0x8d:   /* TODO: RI */
-	case 0x8e:   /* TODO: SS2 */
-	case 0x8f:   /* TODO: SS3 */
+	case 0x8e:   /* SS2 -- Single shift 2 */
+	case 0x8f:   /* SS3 -- Single shift 3 */
+		term.sshift = 2 + (ascii - 0x8e);
+		break;
 	case 0x91:   /* TODO: PU1 */
 	case 0x92:   /* TODO: PU2 */
 	case 0x93:   /* TODO: STS */
@@ -2687,6 +5441,19 @@ eschandle(uchar ascii)
 	case 'o': /* LS3 -- Locking shift 3 */
 		term.charset = 2 + (ascii - 'n');
 		break;
+	case '~': /* LS1R -- Locking shift 1, right */
+		term.charsetr = 1;
+		break;
+	case '}': /* LS2R -- Locking shift 2, right */
+		term.charsetr = 2;
+		break;
+	case '|': /* LS3R -- Locking shift 3, right */
+		term.charsetr = 3;
+		break;
+	case 'N': /* SS2 -- Single shift 2 */
+	case 'O': /* SS3 -- Single shift 3 */
+		term.sshift = 2 + (ascii - 'N');
+		break;
 	case '(': /* GZD4 -- set primary charset G0 */
 	case ')': /* G1D4 -- set secondary charset G1 */
 	case '*': /* G2D4 -- set tertiary charset G2 */
@@ -2694,6 +5461,13 @@ eschandle(uchar ascii)
 		term.icharset = ascii - '(';
 		term.esc |= ESC_ALTCHARSET;
 		return 0;
+	case '-': /* G1D6 -- set secondary 96 charset G1 */
+	case '.': /* G2D6 -- set tertiary 96 charset G2 */
+	case '/': /* G3D6 -- set quaternary 96 charset G3 */
+		/* tdeftran tells the 96 character sets by the 4 bit */
+		term.icharset = 4 | (ascii - ',');
+		term.esc |= ESC_ALTCHARSET;
+		return 0;
 	case 'D': /* IND -- Linefeed */
 		if (term.c.y == term.bot) {
 			tscrollup(term.top, 1, 1);
@@ -2719,7 +5493,7 @@ eschandle(uchar ascii)
 		break;
 	case 'c': /* RIS -- Reset to inital state */
 		treset();
-		xresettitle();
+		tsettitle(NULL);
 		xloadcols();
 		break;
 	case '=': /* DECPAM -- Application keypad */
@@ -2746,12 +5520,95 @@ eschandle(uchar ascii)
 	return 1;
 }
 
+/*
+ * The VT52 escape sequences, which replace the ANSI ones while DECANM is
+ * reset. Returns like eschandle.
+ */
+int
+vt52handle(uchar ascii)
+{
+	if (term.esc & ESC_VT52Y) {
+		/* ESC Y row col, both offset by 040 */
+		csiescseq.arg[csiescseq.narg++] = ascii - 040;
+		if (csiescseq.narg < 2)
+			return 0;
+		tmoveto(csiescseq.arg[1], csiescseq.arg[0]);
+		return 1;
+	}
+
+	switch (ascii) {
+	case 'A': /* cursor up */
+		tmoveto(term.c.x, term.c.y-1);
+		break;
+	case 'B': /* cursor down */
+		tmoveto(term.c.x, term.c.y+1);
+		break;
+	case 'C': /* cursor right */
+		tmoveto(term.c.x+1, term.c.y);
+		break;
+	case 'D': /* cursor left */
+		tmoveto(term.c.x-1, term.c.y);
+		break;
+	case 'F': /* enter graphics mode */
+		term.trantbl[term.charset] = CS_GRAPHIC0;
+		break;
+	case 'G': /* exit graphics mode */
+		term.trantbl[term.charset] = CS_USA;
+		break;
+	case 'H': /* cursor home */
+		tmoveto(0, 0);
+		break;
+	case 'I': /* reverse line feed */
+		if (term.c.y == term.top) {
+			tscrolldown(term.top, 1, 1);
+		} else {
+			tmoveto(term.c.x, term.c.y-1);
+		}
+		break;
+	case 'J': /* erase to end of screen */
+		tclearregion(term.c.x, term.c.y, term.col-1, term.c.y);
+		if (term.c.y < term.row-1)
+			tclearregion(0, term.c.y+1, term.col-1, term.row-1);
+		break;
+	case 'K': /* erase to end of line */
+		tclearregion(term.c.x, term.c.y, term.col-1, term.c.y);
+		break;
+	case 'Y': /* direct cursor address */
+		term.esc |= ESC_VT52Y;
+		return 0;
+	case 'Z': /* identify */
+		ttywrite("\033/Z", 3);
+		break;
+	case '=': /* alternate keypad */
+		term.mode |= MODE_APPKEYPAD;
+		break;
+	case '>': /* numeric keypad */
+		term.mode &= ~MODE_APPKEYPAD;
+		break;
+	case '<': /* enter ANSI mode */
+		term.mode &= ~MODE_VT52;
+		break;
+	case 'V': /* print cursor line (IGNORED) */
+	case 'W': /* enter printer controller mode (IGNORED) */
+	case 'X': /* exit printer controller mode (IGNORED) */
+	case ']': /* print screen (IGNORED) */
+	case '^': /* auto print on (IGNORED) */
+	case '_': /* auto print off (IGNORED) */
+		break;
+	default:
+		fprintf(stderr, "erresc: unknown VT52 sequence ESC 0x%02X '%c'\n",
+			(uchar) ascii, isprint(ascii)? ascii:'.');
+		break;
+	}
+	return 1;
+}
+
 void
 tputc(Rune u)
 {
 	char c[UTF_SIZ];
 	int control;
-	int width, len;
+	int width, len, edge;
 	Glyph *gp;
 
 	control = ISCONTROL(u);
@@ -2775,7 +5632,10 @@ tputc(Rune u)
 		   ISCONTROLC1(u)) {
 			term.esc &= ~(ESC_START|ESC_STR);
 			term.esc |= ESC_STR_END;
-		} else if (strescseq.len + len < sizeof(strescseq.buf) - 1) {
+		} else if (strescseq.len + len < strescseq.siz - 1 ||
+		           (strescseq.siz < STR_BUF_MAX &&
+		            (strescseq.buf = xrealloc(strescseq.buf,
+		                                      strescseq.siz *= 2)))) {
 			memmove(&strescseq.buf[strescseq.len], c, len);
 			strescseq.len += len;
 			return;
@@ -2804,6 +5664,8 @@ tputc(Rune u)
 	 */
 	if (control) {
 		tcontrolcode(u);
+		if (!term.esc)
+			term.lastc = 0;
 		/*
 		 * control codes are not shown ever
 		 */
@@ -2820,9 +5682,13 @@ tputc(Rune u)
 			}
 			return;
 		} else if (term.esc & ESC_ALTCHARSET) {
-			tdeftran(u);
+			if (!tdeftran(u))
+				return;
 		} else if (term.esc & ESC_TEST) {
 			tdectest(u);
+		} else if (IS_SET(MODE_VT52)) {
+			if (!vt52handle(u))
+				return;
 		} else {
 			if (!eschandle(u))
 				return;
@@ -2845,28 +5711,36 @@ tputc(Rune u)
 		gp = &term.line[term.c.y][term.c.x];
 	}
 
-	if (IS_SET(MODE_INSERT) && term.c.x+width < term.col)
-		memmove(gp+width, gp, (term.col - term.c.x - width) * sizeof(Glyph));
+	/* wrap at the right margin, unless the cursor is already past it */
+	edge = term.c.x > term.right ? term.col : term.right+1;
 
-	if (term.c.x+width > term.col) {
+	if (IS_SET(MODE_INSERT) && term.c.x+width < edge)
+		memmove(gp+width, gp, (edge - term.c.x - width) * sizeof(Glyph));
+
+	if (term.c.x+width > edge) {
 		tnewline(1);
 		gp = &term.line[term.c.y][term.c.x];
+		edge = term.c.x > term.right ? term.col : term.right+1;
 	}
 
 	tsetchar(u, &term.c.attr, term.c.x, term.c.y);
 
 	if (width == 2) {
 		gp->mode |= ATTR_WIDE;
-		if (term.c.x+1 < term.col) {
+		if (term.c.x+1 < edge) {
 			gp[1].u = '\0';
 			gp[1].mode = ATTR_WDUMMY;
+			gp[1].link = gp->link;
+			gp[1].img = 0;
 		}
 	}
-	if (term.c.x+width < term.col) {
+	if (term.c.x+width < edge) {
 		tmoveto(term.c.x+width, term.c.y);
 	} else {
 		term.c.state |= CURSOR_WRAPNEXT;
 	}
+	term.lastc = u;
+	term.sshift = 0;
 }
 
 void
@@ -2945,6 +5819,7 @@ tresize(int col, int row)
 	term.row = row;
 	/* reset scrolling region */
 	tsetscroll(0, row-1);
+	tsetmargins(0, col-1);
 	/* make use of the LIMIT in tmoveto */
 	tmoveto(term.c.x, term.c.y);
 	/* Clearing both screens (it makes dirty all lines) */
@@ -2993,6 +5868,21 @@ xsetcolorname(int x, const char *name)
 	return 0;
 }
 
+void
+xcolorchanged(int x)
+{
+	/*
+	 * The borders are only cleared as a side effect of drawing the
+	 * glyphs next to them, and the window background is what X fills
+	 * in on resize, so both need the new color.
+	 */
+	if (x == defaultbg) {
+		XSetWindowBackground(xw.dpy, xw.win, dc.col[defaultbg].pixel);
+		xclear(0, 0, xw.w, xw.h);
+	}
+	redraw();
+}
+
 /*
  * Absolute coordinates.
  */
@@ -3287,8 +6177,8 @@ xdrawglyphfontspecs(const XftGlyphFontSpec *specs, Glyph base, int len, int x, i
 	int charlen = len * ((base.mode & ATTR_WIDE) ? 2 : 1);
 	int winx = borderpx + x * xw.cw, winy = borderpx + y * xw.ch,
 	    width = charlen * xw.cw;
-	Color *fg, *bg, *temp, revfg, revbg, truefg, truebg;
-	XRenderColor colfg, colbg;
+	Color *fg, *bg, *ul, *temp, revfg, revbg, truefg, truebg, trueul;
+	XRenderColor colfg, colbg, colul;
 	XRectangle r;
 
 	/* Determine foreground and background colors based on mode. */
@@ -3402,8 +6292,23 @@ xdrawglyphfontspecs(const XftGlyphFontSpec *specs, Glyph base, int len, int x, i
 
 	/* Render underline and strikethrough. */
 	if (base.mode & ATTR_UNDERLINE) {
-		XftDrawRect(xw.draw, fg, winx, winy + dc.font.ascent + 1,
-				width, 1);
+		ul = fg;
+		if ((base.ul & UL_COLOR) && !(base.mode & ATTR_INVISIBLE) &&
+		   !(base.mode & ATTR_BLINK && term.mode & MODE_BLINK)) {
+			if (IS_TRUECOL(base.ulcolor)) {
+				colul.alpha = 0xffff;
+				colul.red = TRUERED(base.ulcolor);
+				colul.green = TRUEGREEN(base.ulcolor);
+				colul.blue = TRUEBLUE(base.ulcolor);
+				XftColorAllocValue(xw.dpy, xw.vis, xw.cmap,
+				                   &colul, &trueul);
+				ul = &trueul;
+			} else {
+				ul = &dc.col[base.ulcolor];
+			}
+		}
+		xdrawunderline(ul, base.ul & UL_STYLE, winx,
+		               winy + dc.font.ascent + 1, width);
 	}
 
 	if (base.mode & ATTR_STRUCK) {
@@ -3415,6 +6320,56 @@ xdrawglyphfontspecs(const XftGlyphFontSpec *specs, Glyph base, int len, int x, i
 	XftDrawSetClip(xw.draw, 0);
 }
 
+void
+xdrawunderline(Color *c, int style, int x, int y, int width)
+{
+	/* a wave 8 pixels long, kept in phase across runs */
+	static const int wave[8] = { 0, 0, 1, 1, 2, 1, 1, 0 };
+	int i, n;
+
+	switch (style) {
+	case UL_DOUBLE:
+		XftDrawRect(xw.draw, c, x, y, width, 1);
+		XftDrawRect(xw.draw, c, x, y + 2, width, 1);
+		break;
+	case UL_CURLY:
+		/* one rect for each stretch at the same height */
+		for (i = x; i < x + width; i += n) {
+			for (n = 1; i + n < x + width &&
+			            wave[(i + n) % 8] == wave[i % 8]; n++)
+				;
+			XftDrawRect(xw.draw, c, i, y + wave[i % 8], n, 1);
+		}
+		break;
+	case UL_DOTTED:
+		xdrawdashes(c, x, y, width, 4, 2);
+		break;
+	case UL_DASHED:
+		xdrawdashes(c, x, y, width, 8, 5);
+		break;
+	default:
+		XftDrawRect(xw.draw, c, x, y, width, 1);
+		break;
+	}
+}
+
+/*
+ * Draws dashes len pixels long every period pixels, lined up on multiples
+ * of period so they stay in phase across runs.
+ */
+void
+xdrawdashes(Color *c, int x, int y, int width, int period, int len)
+{
+	int i, x1, x2;
+
+	for (i = x - x % period; i < x + width; i += period) {
+		x1 = MAX(i, x);
+		x2 = MIN(i + len, x + width);
+		if (x2 > x1)
+			XftDrawRect(xw.draw, c, x1, y, x2 - x1, 1);
+	}
+}
+
 void
 redraw(void)
 {
@@ -3442,13 +6397,6 @@ unmap(XEvent *ev)
 	xw.state &= ~WIN_VISIBLE;
 }
 
-void
-xsetpointermotion(int set)
-{
-	MODBIT(xw.attrs.event_mask, set, PointerMotionMask);
-	XChangeWindowAttributes(xw.dpy, xw.win, CWEventMask, &xw.attrs);
-}
-
 void
 xseturgency(int add)
 {
@@ -3497,6 +6445,7 @@ char*
 kmap(KeySym k, uint state)
 {
 	Key *kp;
+	char *s;
 	int i;
 
 	/* Check for mapped keys out of X11 function keys. */
@@ -3509,6 +6458,9 @@ kmap(KeySym k, uint state)
 			return NULL;
 	}
 
+	if (IS_SET(MODE_VT52) && (s = vt52key(k)))
+		return s;
+
 	for (kp = key; kp < key + LEN(key); kp++) {
 		if (kp->k != k)
 			continue;
@@ -3533,6 +6485,61 @@ kmap(KeySym k, uint state)
 	return NULL;
 }
 
+/*
+ * Cursor and keypad keys in VT52 mode. The keypad only sends its own
+ * sequences in alternate keypad mode, otherwise key[] is used.
+ */
+char *
+vt52key(KeySym k)
+{
+	static struct {
+		KeySym k;
+		char *s;
+		int appkey;
+	} keys[] = {
+		{ XK_Up,          "\033A",  0 },
+		{ XK_KP_Up,       "\033A",  0 },
+		{ XK_Down,        "\033B",  0 },
+		{ XK_KP_Down,     "\033B",  0 },
+		{ XK_Right,       "\033C",  0 },
+		{ XK_KP_Right,    "\033C",  0 },
+		{ XK_Left,        "\033D",  0 },
+		{ XK_KP_Left,     "\033D",  0 },
+		{ XK_F1,          "\033P",  0 },
+		{ XK_KP_F1,       "\033P",  0 },
+		{ XK_F2,          "\033Q",  0 },
+		{ XK_KP_F2,       "\033Q",  0 },
+		{ XK_F3,          "\033R",  0 },
+		{ XK_KP_F3,       "\033R",  0 },
+		{ XK_F4,          "\033S",  0 },
+		{ XK_KP_F4,       "\033S",  0 },
+		{ XK_KP_0,        "\033?p", 1 },
+		{ XK_KP_1,        "\033?q", 1 },
+		{ XK_KP_2,        "\033?r", 1 },
+		{ XK_KP_3,        "\033?s", 1 },
+		{ XK_KP_4,        "\033?t", 1 },
+		{ XK_KP_5,        "\033?u", 1 },
+		{ XK_KP_6,        "\033?v", 1 },
+		{ XK_KP_7,        "\033?w", 1 },
+		{ XK_KP_8,        "\033?x", 1 },
+		{ XK_KP_9,        "\033?y", 1 },
+		{ XK_KP_Separator, "\033?l", 1 },
+		{ XK_KP_Subtract, "\033?m", 1 },
+		{ XK_KP_Decimal,  "\033?n", 1 },
+		{ XK_KP_Enter,    "\033?M", 1 },
+	};
+	int i;
+
+	for (i = 0; i < LEN(keys); i++) {
+		if (keys[i].k != k)
+			continue;
+		if (keys[i].appkey && !IS_SET(MODE_APPKEYPAD))
+			return NULL;
+		return keys[i].s;
+	}
+	return NULL;
+}
+
 
 
 